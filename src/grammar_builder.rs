@@ -0,0 +1,179 @@
+use std::{
+    env::{
+        self,
+        consts::{DLL_PREFIX, DLL_SUFFIX},
+    },
+    ffi::OsString,
+    fs::{self, read, read_dir},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+/// Sources of a grammar in the `src` directory of a tree-sitter grammar repository
+struct Sources {
+    src: PathBuf,
+    parser: PathBuf,
+    /// External scanner in C or C++
+    scanner: Option<PathBuf>,
+}
+
+impl Sources {
+    fn new(dir: &Path) -> anyhow::Result<Sources> {
+        let src = dir.join("src");
+        let parser = src.join("parser.c");
+        if !parser.is_file() {
+            return Err(anyhow::format_err!(
+                "cannot find grammar source {}",
+                parser.display()
+            ));
+        }
+        let scanner = ["scanner.c", "scanner.cc"]
+            .iter()
+            .map(|name| src.join(name))
+            .find(|scanner| scanner.is_file());
+        Ok(Sources {
+            src,
+            parser,
+            scanner,
+        })
+    }
+
+    fn is_cpp(&self) -> bool {
+        self.scanner
+            .as_ref()
+            .is_some_and(|scanner| scanner.extension().is_some_and(|ext| ext == "cc"))
+    }
+
+    /// C compiler, or C++ compiler if scanner is written in C++, `$CC` and `$CXX` are preferred
+    fn compiler(&self) -> OsString {
+        let (var, default) = if self.is_cpp() {
+            ("CXX", "c++")
+        } else {
+            ("CC", "cc")
+        };
+        env::var_os(var)
+            .filter(|compiler| !compiler.is_empty())
+            .unwrap_or_else(|| default.into())
+    }
+
+    /// Hash of compiler and the content of sources, including headers in `src/tree_sitter`
+    fn hash(&self) -> anyhow::Result<String> {
+        let mut files = vec![self.parser.clone()];
+        files.extend(self.scanner.clone());
+        if let Ok(entries) = read_dir(self.src.join("tree_sitter")) {
+            let mut headers = vec![];
+            for entry in entries {
+                headers.push(entry?.path());
+            }
+            headers.sort();
+            files.extend(headers);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.compiler().to_string_lossy().as_bytes());
+        for file in files {
+            let content = read(&file)
+                .with_context(|| format!("Failed to read grammar source {}", file.display()))?;
+            hasher.update(
+                file.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .as_bytes(),
+            );
+            // Lengths keep contents of different files apart
+            hasher.update((content.len() as u64).to_le_bytes());
+            hasher.update(&content);
+        }
+        let hash = hasher.finalize();
+        Ok(hash.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+/// Cache directory of compiled grammars, `$XDG_CACHE_HOME/tree-sed/grammars`
+/// or `~/.cache/tree-sed/grammars`
+fn cache_dir() -> anyhow::Result<PathBuf> {
+    let dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME").context("cannot find cache directory")?)
+            .join(".cache"),
+    };
+    Ok(dir.join("tree-sed").join("grammars"))
+}
+
+/// Compile grammar `name` in source directory `dir` to a shared library, and return its path.
+/// The library is cached by the hash of sources, so it's compiled again only if they change
+pub fn build_grammar(dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    let sources = Sources::new(dir)?;
+    let cache_dir = cache_dir()?;
+    let library = cache_dir.join(format!(
+        "{}tree-sitter-{}-{}{}",
+        DLL_PREFIX,
+        name,
+        &sources.hash()?[..16],
+        DLL_SUFFIX
+    ));
+    if library.is_file() {
+        return Ok(library);
+    }
+    fs::create_dir_all(&cache_dir)
+        .with_context(|| format!("Failed to create cache directory {}", cache_dir.display()))?;
+    // Library is renamed after compiled, so other processes never load a partial one
+    let temp = library.with_extension(format!("{}.tmp", std::process::id()));
+    let mut command = Command::new(sources.compiler());
+    command
+        .args(["-shared", "-fPIC", "-O2", "-I"])
+        .arg(&sources.src)
+        .arg("-o")
+        .arg(&temp);
+    if sources.is_cpp() {
+        command.arg("-xc").arg(&sources.parser).arg("-xc++");
+    } else {
+        command.arg(&sources.parser);
+    }
+    command.args(&sources.scanner);
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run compiler {:?}", sources.compiler()))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&temp);
+        return Err(anyhow::format_err!(
+            "Failed to compile grammar {}\n{}",
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    fs::rename(&temp, &library)
+        .with_context(|| format!("Failed to write {}", library.display()))?;
+    Ok(library)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_sources_hash() {
+        let temp_dir = TempDir::new("sources");
+        let dir = temp_dir.path();
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("tree_sitter")).unwrap();
+        assert!(Sources::new(dir).is_err());
+        fs::write(src.join("parser.c"), "int parser;").unwrap();
+        fs::write(src.join("tree_sitter").join("parser.h"), "int header;").unwrap();
+        let sources = Sources::new(dir).unwrap();
+        assert!(sources.scanner.is_none());
+        let hash = sources.hash().unwrap();
+        assert_eq!(hash, sources.hash().unwrap());
+        // Hash is changed by scanner and headers
+        fs::write(src.join("scanner.cc"), "int scanner;").unwrap();
+        let sources = Sources::new(dir).unwrap();
+        assert!(sources.is_cpp());
+        let scanner_hash = sources.hash().unwrap();
+        assert_ne!(hash, scanner_hash);
+        fs::write(src.join("tree_sitter").join("parser.h"), "int header2;").unwrap();
+        assert_ne!(scanner_hash, sources.hash().unwrap());
+    }
+}
@@ -0,0 +1,518 @@
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use libloading::{Library, Symbol};
+use serde::Deserialize;
+use tree_sitter::{Language, LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
+
+use crate::grammar_builder::build_grammar;
+
+/// Lines at the start and the end of source code searched for modelines, like vim
+const MODELINES: usize = 5;
+
+/// Bytes read at the start and the end of files to detect their languages
+const DETECTION_BLOCK: u64 = 4096;
+
+/// A grammar and the names used to detect its source code
+pub struct Grammar {
+    /// Name given by `--language`
+    pub name: String,
+    pub language: Language,
+    /// File extensions without the leading `.`
+    pub extensions: Vec<String>,
+    /// Names of the language in modelines, e.g. `c++` of `-*- mode: c++ -*-`
+    pub aliases: Vec<String>,
+    /// Interpreters in shebang lines, e.g. `rust-script` of `#!/usr/bin/env rust-script`
+    pub interpreters: Vec<String>,
+}
+
+impl Grammar {
+    fn new(
+        name: &str,
+        language: Language,
+        extensions: &[&str],
+        aliases: &[&str],
+        interpreters: &[&str],
+    ) -> Grammar {
+        let strings = |strs: &[&str]| strs.iter().map(|s| s.to_string()).collect();
+        Grammar {
+            name: name.to_string(),
+            language,
+            extensions: strings(extensions),
+            aliases: strings(aliases),
+            interpreters: strings(interpreters),
+        }
+    }
+}
+
+/// Grammars available to parse source code
+pub struct Registry {
+    grammars: Vec<Grammar>,
+}
+
+impl Registry {
+    /// Registry of grammars compiled in by features
+    pub fn builtin() -> Registry {
+        let grammars = vec![
+            #[cfg(feature = "c")]
+            Grammar::new(
+                "c",
+                tree_sitter_c::language(),
+                &["c", "h"],
+                &["c"],
+                &["tcc"],
+            ),
+            #[cfg(feature = "cpp")]
+            Grammar::new(
+                "cpp",
+                tree_sitter_cpp::language(),
+                &["cc", "cpp", "cxx", "hpp", "hh", "hxx"],
+                &["cpp", "c++"],
+                &["cling"],
+            ),
+            #[cfg(feature = "rust")]
+            Grammar::new(
+                "rust",
+                tree_sitter_rust::language(),
+                &["rs"],
+                &["rust"],
+                &["rust-script", "run-cargo-script"],
+            ),
+        ];
+        Registry { grammars }
+    }
+
+    /// Add `grammar` before other grammars, so it's preferred by detection.
+    /// The grammar of the same name is replaced
+    fn add(&mut self, grammar: Grammar) {
+        self.grammars.retain(|g| g.name != grammar.name);
+        self.grammars.insert(0, grammar);
+    }
+
+    /// Load grammar from shared library or source directory at `path`, its name is taken
+    /// from the file name, e.g. `python` of `libtree-sitter-python.so` or `tree-sitter-python`
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let name = grammar_name(path)
+            .with_context(|| format!("cannot get grammar name of {}", path.display()))?;
+        let language = if path.is_dir() {
+            load_language(&build_grammar(path, &name)?, &name)?
+        } else {
+            load_language(path, &name)?
+        };
+        self.add(Grammar::new(&name, language, &[], &[&name], &[]));
+        Ok(())
+    }
+
+    /// Load grammars in config file at `path`
+    pub fn load_config(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let config: ConfigFile = toml::from_str(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        // Relative paths are relative to the directory of config file
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for (name, grammar) in config.grammar {
+            let library = match (grammar.library, grammar.source) {
+                (Some(library), None) => dir.join(library),
+                (None, Some(source)) => build_grammar(&dir.join(source), &name)?,
+                _ => {
+                    return Err(anyhow::format_err!(
+                        "grammar {} needs either `library` or `source` in config file {}",
+                        name,
+                        path.display()
+                    ))
+                }
+            };
+            let language = load_language(&library, &name)?;
+            let mut aliases = grammar.aliases;
+            if !aliases.contains(&name) {
+                aliases.push(name.clone());
+            }
+            self.add(Grammar {
+                name,
+                language,
+                extensions: grammar.extensions,
+                aliases,
+                interpreters: grammar.interpreters,
+            });
+        }
+        Ok(())
+    }
+
+    /// Names of all grammars, separated by `, `
+    fn names(&self) -> String {
+        let names: Vec<&str> = self.grammars.iter().map(|g| g.name.as_str()).collect();
+        names.join(", ")
+    }
+
+    /// Get the language named `name`
+    pub fn get(&self, name: &str) -> anyhow::Result<Language> {
+        self.grammars
+            .iter()
+            .find(|grammar| grammar.name == name)
+            .map(|grammar| grammar.language)
+            .with_context(|| {
+                format!(
+                    "you don't have {} parser, available languages: {}",
+                    name,
+                    self.names()
+                )
+            })
+    }
+
+    /// Detect language of source code by its modelines, the extension of `file_name`,
+    /// or its shebang line in order
+    pub fn detect(&self, file_name: Option<&Path>, source_code: &[u8]) -> anyhow::Result<Language> {
+        let by_alias = |alias: &str| {
+            self.grammars
+                .iter()
+                .find(|g| g.aliases.iter().any(|a| a.eq_ignore_ascii_case(alias)))
+        };
+        let by_extension = |extension: &str| {
+            self.grammars
+                .iter()
+                .find(|g| g.extensions.iter().any(|e| e == extension))
+        };
+        let by_interpreter = |interpreter: &str| {
+            self.grammars
+                .iter()
+                .find(|g| g.interpreters.iter().any(|i| i == interpreter))
+        };
+        let grammar = modeline(source_code)
+            .and_then(by_alias)
+            .or_else(|| {
+                file_name
+                    .and_then(|file_name| file_name.extension())
+                    .and_then(|extension| extension.to_str())
+                    .and_then(by_extension)
+            })
+            .or_else(|| shebang(source_code).and_then(by_interpreter));
+        grammar.map(|grammar| grammar.language).with_context(|| {
+            format!(
+                "cannot detect language of {}, choose one of {} by `--language`",
+                file_name.map_or("standard input".into(), |f| f.display().to_string()),
+                self.names()
+            )
+        })
+    }
+}
+
+/// Grammars in config file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    grammar: BTreeMap<String, GrammarConfig>,
+}
+
+/// A grammar in config file, named by its table, e.g. `[grammar.python]`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GrammarConfig {
+    /// Path of shared library
+    library: Option<PathBuf>,
+    /// Path of grammar repository with `src/parser.c`, compiled to a cached shared library
+    source: Option<PathBuf>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    interpreters: Vec<String>,
+}
+
+/// Default config file, `$XDG_CONFIG_HOME/tree-sed/config.toml` or `~/.config/tree-sed/config.toml`
+pub fn default_config() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("tree-sed").join("config.toml"))
+}
+
+/// Get grammar name from the file name of shared library or source directory,
+/// without `lib` and `tree-sitter-` prefix
+fn grammar_name(path: &Path) -> Option<String> {
+    // Dots in names of directories are not extensions
+    let stem = if path.is_dir() {
+        path.file_name()?.to_str()?
+    } else {
+        path.file_stem()?.to_str()?
+    };
+    let stem = stem.strip_prefix("lib").unwrap_or(stem);
+    let name = ["tree-sitter-", "tree_sitter_"]
+        .iter()
+        .find_map(|prefix| stem.strip_prefix(prefix))
+        .unwrap_or(stem);
+    Some(name.to_string()).filter(|name| !name.is_empty())
+}
+
+/// Load language by the `tree_sitter_{name}` function of shared library at `path`
+fn load_language(path: &Path, name: &str) -> anyhow::Result<Language> {
+    let symbol = format!("tree_sitter_{}", name.replace('-', "_"));
+    // Bare file name is searched in system library paths, instead of the current directory
+    let path = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new(".").join(path),
+        _ => path.to_path_buf(),
+    };
+    // SAFETY: the library is a tree-sitter grammar, whose initializers are harmless,
+    // and whose `tree_sitter_{name}` function returns a pointer to its language
+    let language = unsafe {
+        let library = Library::new(&path)
+            .with_context(|| format!("Failed to load grammar {}", path.display()))?;
+        let function: Symbol<unsafe extern "C" fn() -> Language> =
+            library
+                .get(symbol.as_bytes())
+                .with_context(|| format!("cannot find {} in {}", symbol, path.display()))?;
+        let language = function();
+        // Language refers to data in the library, so the library is never unloaded
+        std::mem::forget(library);
+        language
+    };
+    let version = language.version();
+    if !(MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+        return Err(anyhow::format_err!(
+            "grammar {} has ABI version {}, but only versions {} to {} are supported",
+            path.display(),
+            version,
+            MIN_COMPATIBLE_LANGUAGE_VERSION,
+            LANGUAGE_VERSION
+        ));
+    }
+    Ok(language)
+}
+
+/// Get the interpreter of shebang line, e.g. `rust-script` of `#!/usr/bin/env rust-script`
+fn shebang(source_code: &[u8]) -> Option<&str> {
+    let line = source_code.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line.strip_prefix(b"#!")?).ok()?;
+    // `#![...]` is an inner attribute of rust
+    if line.trim_start().starts_with('[') {
+        return None;
+    }
+    let mut words = line.split_whitespace();
+    let interpreter = words.next()?.rsplit('/').next()?;
+    match interpreter {
+        // Options of `env`, such as `-S`, are skipped
+        "env" => words.find(|word| !word.starts_with('-') && !word.contains('=')),
+        _ => Some(interpreter),
+    }
+}
+
+/// Read the first and the last `MODELINES` lines of file at `path`, where shebang and modelines
+/// are, small files are read as a whole
+pub fn read_head_and_tail(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut head = vec![];
+    if len <= DETECTION_BLOCK * 2 {
+        file.read_to_end(&mut head)?;
+        return Ok(head);
+    }
+    (&mut file).take(DETECTION_BLOCK).read_to_end(&mut head)?;
+    let mut tail = vec![];
+    file.seek(SeekFrom::Start(len - DETECTION_BLOCK))?;
+    file.read_to_end(&mut tail)?;
+    let newlines = |block: &[u8]| -> Vec<usize> {
+        block
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .map(|(i, _)| i)
+            .collect()
+    };
+    // Lines cut by blocks are kept, they are still among the first or the last lines
+    match newlines(&head).get(MODELINES - 1) {
+        Some(&end) => head.truncate(end + 1),
+        None => head.push(b'\n'),
+    }
+    let body = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    let start = newlines(body)
+        .iter()
+        .rev()
+        .nth(MODELINES - 1)
+        .map_or(0, |&end| end + 1);
+    head.extend_from_slice(&tail[start..]);
+    Ok(head)
+}
+
+/// Get the language name in modelines, like vim's `vim: set ft=c:` or emacs's `-*- mode: c++ -*-`
+fn modeline(source_code: &[u8]) -> Option<&str> {
+    let source_code = source_code.strip_suffix(b"\n").unwrap_or(source_code);
+    let lines: Vec<&[u8]> = source_code.split(|&b| b == b'\n').collect();
+    let tail = lines.len().saturating_sub(MODELINES).max(MODELINES);
+    lines[..MODELINES.min(lines.len())]
+        .iter()
+        .chain(lines.get(tail..).unwrap_or_default())
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .find_map(|line| emacs_modeline(line).or_else(|| vim_modeline(line)))
+}
+
+/// Get the mode of `-*- mode: c++ -*-` or `-*- c++ -*-`
+fn emacs_modeline(line: &str) -> Option<&str> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (variables, _) = rest.split_once("-*-")?;
+    let variables = variables.trim();
+    if !variables.contains(':') {
+        return Some(variables).filter(|mode| !mode.is_empty());
+    }
+    variables.split(';').find_map(|variable| {
+        let (name, value) = variable.split_once(':')?;
+        Some(value.trim()).filter(|_| name.trim().eq_ignore_ascii_case("mode"))
+    })
+}
+
+/// Get the file type of `vim: set ft=c:` or `vi: filetype=c`
+fn vim_modeline(line: &str) -> Option<&str> {
+    let start = ["vim:", "vi:", "ex:"]
+        .iter()
+        .filter_map(|marker| {
+            let start = line.find(marker)?;
+            // Markers are preceded by white space, unless it is at the start of line
+            let preceded = start == 0 || line[..start].ends_with(char::is_whitespace);
+            Some(start + marker.len()).filter(|_| preceded)
+        })
+        .min()?;
+    line[start..]
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .find_map(|option| {
+            let (name, value) = option.split_once('=')?;
+            Some(value).filter(|_| matches!(name, "ft" | "filetype" | "syntax" | "syn"))
+        })
+}
+
+#[cfg(all(test, feature = "c", feature = "cpp", feature = "rust"))]
+mod test {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    /// Get the name of detected language
+    fn detect(file_name: Option<&str>, source_code: &str) -> Option<String> {
+        let registry = Registry::builtin();
+        let language = registry
+            .detect(file_name.map(Path::new), source_code.as_bytes())
+            .ok()?;
+        registry
+            .grammars
+            .iter()
+            .find(|grammar| grammar.language == language)
+            .map(|grammar| grammar.name.clone())
+    }
+
+    #[test]
+    fn test_detect_extension() {
+        assert_eq!(detect(Some("src/main.c"), "").as_deref(), Some("c"));
+        assert_eq!(detect(Some("main.h"), "").as_deref(), Some("c"));
+        assert_eq!(detect(Some("main.cc"), "").as_deref(), Some("cpp"));
+        assert_eq!(detect(Some("main.hpp"), "").as_deref(), Some("cpp"));
+        assert_eq!(detect(Some("main.rs"), "").as_deref(), Some("rust"));
+        assert_eq!(detect(Some("main.py"), ""), None);
+        assert_eq!(detect(None, "int main() {}"), None);
+    }
+
+    #[test]
+    fn test_detect_shebang() {
+        assert_eq!(
+            detect(Some("run"), "#!/usr/bin/env rust-script\nfn main() {}").as_deref(),
+            Some("rust")
+        );
+        assert_eq!(
+            detect(None, "#!/usr/bin/env -S rust-script -q\nfn main() {}").as_deref(),
+            Some("rust")
+        );
+        assert_eq!(
+            detect(None, "#!/usr/bin/tcc -run\nint main() {}").as_deref(),
+            Some("c")
+        );
+        // Inner attribute of rust isn't a shebang
+        assert_eq!(detect(None, "#![allow(unused)]\nfn main() {}"), None);
+    }
+
+    #[test]
+    fn test_detect_modeline() {
+        assert_eq!(
+            detect(Some("list.h"), "// -*- mode: c++ -*-\nclass A {};").as_deref(),
+            Some("cpp")
+        );
+        assert_eq!(
+            detect(Some("list.h"), "/* -*- C++ -*- */\nclass A {};").as_deref(),
+            Some("cpp")
+        );
+        assert_eq!(
+            detect(None, "int a;\n\n\n\n\n\n\n/* vim: set ts=4 ft=c: */\n").as_deref(),
+            Some("c")
+        );
+        assert_eq!(
+            detect(Some("main.c"), "// vi: filetype=rust\n").as_deref(),
+            Some("rust")
+        );
+        // Modelines in the middle of long source code are ignored
+        assert_eq!(detect(None, "\n\n\n\n\n// vim: ft=c\n\n\n\n\n\n"), None);
+        assert_eq!(detect(None, "int navim:ft=c;"), None);
+    }
+
+    #[test]
+    fn test_read_head_and_tail() {
+        let temp_dir = TempDir::new("detect");
+        let path = temp_dir.path().join("a.h");
+        let middle = "int a;\n".repeat(2000);
+        let source_code = format!("// vim: ft=cpp\n\n\n\n\n{}\n\n\n\n// end\n", middle);
+        std::fs::write(&path, &source_code).unwrap();
+        let head_and_tail = read_head_and_tail(&path).unwrap();
+        assert_eq!(
+            String::from_utf8(head_and_tail.clone()).unwrap(),
+            "// vim: ft=cpp\n\n\n\n\n\n\n\n\n// end\n"
+        );
+        let registry = Registry::builtin();
+        assert!(
+            registry.detect(Some(&path), &head_and_tail).unwrap() == tree_sitter_cpp::language()
+        );
+        // Small files are read as a whole
+        std::fs::write(&path, "int a;\n").unwrap();
+        assert_eq!(read_head_and_tail(&path).unwrap(), b"int a;\n");
+    }
+
+    #[test]
+    fn test_grammar_name() {
+        let name = |path: &str| grammar_name(Path::new(path));
+        assert_eq!(
+            name("/usr/lib/libtree-sitter-python.so").as_deref(),
+            Some("python")
+        );
+        assert_eq!(name("tree_sitter_c_sharp.dll").as_deref(), Some("c_sharp"));
+        assert_eq!(name("grammars/toml.so").as_deref(), Some("toml"));
+        assert_eq!(name("libtree-sitter-.so"), None);
+    }
+
+    #[test]
+    fn test_config_file() {
+        let config: ConfigFile = toml::from_str(
+            "[grammar.python]\nlibrary = \"python.so\"\nextensions = [\"py\"]\ninterpreters = [\"python3\"]\n",
+        )
+        .unwrap();
+        let python = &config.grammar["python"];
+        assert_eq!(python.library.as_deref(), Some(Path::new("python.so")));
+        assert_eq!(python.source, None);
+        assert_eq!(python.extensions, ["py"]);
+        assert!(python.aliases.is_empty());
+        assert_eq!(python.interpreters, ["python3"]);
+        // Typos in config file are reported
+        assert!(
+            toml::from_str::<ConfigFile>("[grammar.python]\nlibary = \"python.so\"\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_unknown_language() {
+        let error = Registry::builtin().get("python").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "you don't have python parser, available languages: c, cpp, rust"
+        );
+    }
+}
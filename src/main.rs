@@ -1,75 +1,664 @@
 use std::{
-    fs::{read_to_string, OpenOptions},
+    collections::{BTreeMap, HashMap},
+    fs::{canonicalize, read, read_to_string, OpenOptions},
     io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
 };
 
 use anyhow::Context;
-use clap::{arg, App, Arg};
-#[cfg(feature = "c")]
-use tree_sitter_c::language as c_language;
-#[cfg(feature = "cpp")]
-use tree_sitter_cpp::language as cpp_language;
-#[cfg(feature = "rust")]
-use tree_sitter_rust::language as rust_language;
+use clap::{arg, App, Arg, ArgMatches};
+use encoding_rs::Encoding;
+use ignore::{overrides::OverrideBuilder, WalkBuilder};
+use tree_sitter::Language;
 
+mod grammar_builder;
+mod language;
 mod script_executor;
 mod script_parser;
+#[cfg(test)]
+mod temp_dir;
 
-use script_executor::execute_script;
+use language::{default_config, read_head_and_tail, Registry};
+use script_executor::{execute_script, CompiledScripts, Config, Conflict, LineMatch, Worker};
 use script_parser::parse;
 
+/// Decode source code to UTF-8 if its `encoding` is given, otherwise the bytes are kept as they are
+fn decode(source_code: Vec<u8>, encoding: Option<&'static Encoding>) -> anyhow::Result<Vec<u8>> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(source_code),
+    };
+    // BOM is kept, so it can be written back
+    let (decoded, had_errors) = encoding.decode_without_bom_handling(&source_code);
+    if had_errors {
+        return Err(anyhow::format_err!(
+            "source code is not valid {}",
+            encoding.name()
+        ));
+    }
+    Ok(decoded.into_owned().into_bytes())
+}
+
+/// Encode edited source code back to its original `encoding`
+fn encode(source_code: Vec<u8>, encoding: Option<&'static Encoding>) -> anyhow::Result<Vec<u8>> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(source_code),
+    };
+    let text = String::from_utf8(source_code).context("edited source code is not valid UTF-8")?;
+    let (encoded, _, had_errors) = encoding.encode(&text);
+    if had_errors {
+        return Err(anyhow::format_err!(
+            "edited source code cannot be encoded in {}",
+            encoding.name()
+        ));
+    }
+    Ok(encoded.into_owned())
+}
+
+/// Concatenate scripts from `-e` and `-f` options in the order they are given
+fn script_from_options(matches: &ArgMatches) -> anyhow::Result<Option<String>> {
+    let mut scripts: Vec<(usize, String)> = vec![];
+    if let (Some(indices), Some(values)) = (
+        matches.indices_of("expression"),
+        matches.values_of("expression"),
+    ) {
+        scripts.extend(indices.zip(values.map(String::from)));
+    }
+    if let (Some(indices), Some(values)) = (
+        matches.indices_of("script-file"),
+        matches.values_of("script-file"),
+    ) {
+        for (index, file_name) in indices.zip(values) {
+            let script = read_to_string(file_name)
+                .with_context(|| format!("Failed to read script file {}", file_name))?;
+            scripts.push((index, script));
+        }
+    }
+    if scripts.is_empty() {
+        return Ok(None);
+    }
+    scripts.sort_by_key(|(index, _)| *index);
+    let scripts: Vec<String> = scripts.into_iter().map(|(_, script)| script).collect();
+    Ok(Some(scripts.join("\n")))
+}
+
+/// Expand directories in `paths` to files in them recursively, files ignored by `.gitignore`
+/// or `.ignore`, not matched by `includes` or matched by `excludes` globs are skipped.
+/// Return files with whether they are named in `paths`, a file given more than once is only
+/// kept the first time
+fn input_files(
+    paths: &[&str],
+    includes: &[&str],
+    excludes: &[&str],
+) -> anyhow::Result<Vec<(PathBuf, bool)>> {
+    let mut files: Vec<(PathBuf, bool)> = vec![];
+    let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+    // Files are compared by their canonical paths, e.g. `a.c` and `./a.c`
+    let mut push = |file: PathBuf, named: bool| {
+        let key = canonicalize(&file).unwrap_or_else(|_| file.clone());
+        match seen.get(&key) {
+            Some(&index) => files[index].1 |= named,
+            None => {
+                seen.insert(key, files.len());
+                files.push((file, named));
+            }
+        }
+    };
+    for path in paths {
+        let path = Path::new(path);
+        if !path.is_dir() {
+            push(path.to_path_buf(), true);
+            continue;
+        }
+        let mut excluded = OverrideBuilder::new(path);
+        for exclude in excludes {
+            excluded.add(&format!("!{}", exclude))?;
+        }
+        // Included files are checked after walking, because included globs of overrides
+        // take precedence over ignore files
+        let mut included = OverrideBuilder::new(path);
+        for include in includes {
+            included.add(include)?;
+        }
+        let included = included.build()?;
+        let walker = WalkBuilder::new(path)
+            .overrides(excluded.build()?)
+            .require_git(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            let entry = entry?;
+            if entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+                && (included.is_empty() || included.matched(entry.path(), false).is_whitelist())
+            {
+                push(entry.into_path(), false);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Settings to edit files, shared by workers
+struct Job {
+    scripts: CompiledScripts,
+    config: Config,
+    encoding: Option<&'static Encoding>,
+    in_place: bool,
+    quiet: bool,
+}
+
+/// File or standard input to edit
+struct Input {
+    /// `None` for standard input
+    file_name: Option<PathBuf>,
+    /// Source code read ahead, standard input is always read ahead
+    source_code: Option<Vec<u8>>,
+    lang: Language,
+}
+
+/// Read source code of `file_name`, or standard input if not given
+fn read_source(file_name: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    match file_name {
+        Some(file_name) => {
+            read(file_name).with_context(|| format!("Failed to read {}", file_name.display()))
+        }
+        None => {
+            let mut buf = vec![];
+            io::stdin()
+                .read_to_end(&mut buf)
+                .context("Failed to read standard input")?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Execute scripts on source code of `input`.
+/// Return the content printed by `p` and the edited source code
+fn edit_file(input: &Input, job: &Job, worker: &mut Worker) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let source_code = match &input.source_code {
+        Some(source_code) => source_code.clone(),
+        None => read_source(input.file_name.as_deref())?,
+    };
+    let mut source_code = decode(source_code, job.encoding)?;
+    let output = execute_script(
+        &job.scripts,
+        input.lang,
+        &mut source_code,
+        &job.config,
+        worker,
+    )?;
+    Ok((
+        encode(output, job.encoding)?,
+        encode(source_code, job.encoding)?,
+    ))
+}
+
+/// Write edited `source_code` of `input` back if editing in place, and print `printed` content
+/// of `p` to `output`, followed by the source code if not editing in place
+fn write_file(
+    input: &Input,
+    job: &Job,
+    printed: Vec<u8>,
+    source_code: Vec<u8>,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    match (job.in_place, input.file_name.as_deref()) {
+        (true, Some(file_name)) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(file_name)?;
+            file.write_all(&source_code)?;
+            output.write_all(&printed)?;
+        }
+        (true, None) => return Err(anyhow::format_err!("[FILE] not exist")),
+        _ if job.quiet => output.write_all(&printed)?,
+        _ => {
+            output.write_all(&printed)?;
+            output.write_all(&source_code)?;
+        }
+    }
+    Ok(())
+}
+
+/// Error context of editing `input`
+fn edit_context(input: &Input) -> String {
+    match &input.file_name {
+        Some(file_name) => format!("Failed to edit {}", file_name.display()),
+        None => String::from("Failed to edit standard input"),
+    }
+}
+
+/// Edit `inputs` by `jobs` worker threads, and write them back or print their content to `output`
+/// in the order of `inputs`
+fn edit_files(
+    inputs: &[Input],
+    job: &Job,
+    jobs: usize,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    let next_file = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(inputs.len()) {
+            let (sender, next_file, failed) = (sender.clone(), &next_file, &failed);
+            scope.spawn(move || {
+                let mut worker = Worker::default();
+                // Stop taking files after any file fails
+                while !failed.load(Ordering::Relaxed) {
+                    let index = next_file.fetch_add(1, Ordering::Relaxed);
+                    let input = match inputs.get(index) {
+                        Some(input) => input,
+                        None => break,
+                    };
+                    let result =
+                        edit_file(input, job, &mut worker).with_context(|| edit_context(input));
+                    failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        // Edited file is held until all files before it are written, so no file after a failed
+        // one is written even if it's edited already
+        let mut pending = BTreeMap::new();
+        let mut next_write = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&next_write) {
+                let (printed, source_code) = result?;
+                let input = &inputs[next_write];
+                write_file(input, job, printed, source_code, output)
+                    .with_context(|| edit_context(input))?;
+                next_write += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
 fn main() -> anyhow::Result<()> {
     // TODO add more options to compatible with sed
     let app = App::new("tree-sed")
-        .arg(arg!([SCRIPT]).required(true))
-        .arg(arg!([FILE]))
+        .arg(arg!([SCRIPT]))
+        .arg(arg!([FILE]... "files or directories to edit"))
+        .arg(
+            Arg::new("expression")
+                .short('e')
+                .long("expression")
+                .value_name("SCRIPT")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("add the script to the commands to be executed"),
+        )
+        .arg(
+            Arg::new("script-file")
+                .short('f')
+                .long("file")
+                .value_name("FILE")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("add the contents of script-file to the commands to be executed"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('n')
+                .long("quiet")
+                .alias("silent")
+                .help("suppress automatic printing of source code"),
+        )
         .arg(
             Arg::new("in-place")
                 .short('i')
                 .long("in-place")
                 .help("edit files in place"),
         )
-        .arg(arg!(--language ... "set language").default_value("c"));
+        .arg(
+            Arg::new("language")
+                .long("language")
+                .value_name("NAME")
+                .takes_value(true)
+                .help("set language, or detect it by file extension, shebang and modelines"),
+        )
+        .arg(
+            Arg::new("grammar")
+                .long("grammar")
+                .value_name("PATH")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help(
+                    "load grammar from shared library or source directory, e.g. tree-sitter-python",
+                ),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("load grammars in config file, ~/.config/tree-sed/config.toml by default"),
+        )
+        .arg(
+            Arg::new("line-match")
+                .long("line-match")
+                .value_name("MODE")
+                .takes_value(true)
+                .possible_values(["within", "intersect"])
+                .default_value("within")
+                .help("select nodes lie within or intersect the lines of line address"),
+        )
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .value_name("LABEL")
+                .takes_value(true)
+                .help("decode source code from the encoding, e.g. latin1, and write back in it"),
+        )
+        .arg(
+            Arg::new("conflict")
+                .long("conflict")
+                .value_name("POLICY")
+                .takes_value(true)
+                .possible_values(["error", "outermost", "innermost", "first"])
+                .default_value("error")
+                .help("stop, or keep the outermost, innermost or first edit if edits overlap"),
+        )
+        .arg(
+            Arg::new("include")
+                .long("include")
+                .value_name("GLOB")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("only edit files matching the glob in directories"),
+        )
+        .arg(
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("skip files and directories matching the glob in directories"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("1")
+                .help("edit files by N threads"),
+        );
     let matches = app.get_matches();
-    let script = matches
-        .value_of("SCRIPT")
-        .context("Missing [SCRIPT] argument")?;
-    let script = parse(script).context("[SCRIPT] format error")?;
-    let mut source_code = match matches.value_of("FILE") {
-        Some(file_name) => read_to_string(file_name)?,
-        None => {
-            let mut buf = String::new();
-            io::stdin().read_to_string(&mut buf)?;
-            buf
+    let mut paths: Vec<&str> = vec![];
+    // When script is given by `-e` or `-f`, the first positional argument is input file
+    let script = match script_from_options(&matches)? {
+        Some(script) => {
+            paths.extend(matches.value_of("SCRIPT"));
+            script
+        }
+        None => matches
+            .value_of("SCRIPT")
+            .context("Missing [SCRIPT] argument")?
+            .to_string(),
+    };
+    paths.extend(matches.values_of("FILE").into_iter().flatten());
+    let mut scripts = CompiledScripts::new(parse(&script).context("[SCRIPT] format error")?);
+    let encoding = match matches.value_of("encoding") {
+        Some(label) => {
+            let encoding = Encoding::for_label(label.as_bytes())
+                .with_context(|| format!("unknown encoding {}", label))?;
+            // Encoders of UTF-16 output UTF-8, which can't be written back
+            if encoding.output_encoding() != encoding {
+                return Err(anyhow::format_err!(
+                    "cannot write source code in {}",
+                    encoding.name()
+                ));
+            }
+            Some(encoding)
         }
+        None => None,
     };
+    let mut registry = Registry::builtin();
+    match matches.value_of("config") {
+        Some(config) => registry.load_config(Path::new(config))?,
+        None => {
+            if let Some(config) = default_config().filter(|config| config.exists()) {
+                registry.load_config(&config)?;
+            }
+        }
+    }
+    for library in matches.values_of("grammar").into_iter().flatten() {
+        registry.load(Path::new(library))?;
+    }
     let lang = match matches.value_of("language") {
-        #[cfg(feature = "c")]
-        Some("c") => c_language(),
-        #[cfg(feature = "cpp")]
-        Some("cpp") => cpp_language(),
-        #[cfg(feature = "rust")]
-        Some("rust") => rust_language(),
-        Some(other) => return Err(anyhow::format_err!("you don't have {} parser", other)),
-        None => return Err(anyhow::format_err!("missing `--language` argument")),
+        Some(name) => {
+            let lang = registry.get(name)?;
+            // Queries are compiled before any file is read, so errors of them are reported first
+            scripts.compile(lang)?;
+            Some(lang)
+        }
+        None => None,
+    };
+    let config = Config {
+        line_match: match matches.value_of("line-match") {
+            Some("intersect") => LineMatch::Intersect,
+            _ => LineMatch::Within,
+        },
+        conflict: match matches.value_of("conflict") {
+            Some("outermost") => Conflict::Outermost,
+            Some("innermost") => Conflict::Innermost,
+            Some("first") => Conflict::First,
+            _ => Conflict::Error,
+        },
+    };
+    let globs =
+        |name: &str| -> Vec<&str> { matches.values_of(name).into_iter().flatten().collect() };
+    // Languages of all files are detected and their queries are compiled before editing
+    let mut inputs = vec![];
+    if paths.is_empty() {
+        // Source code is read from standard input if no file is given,
+        // it can only be read once, so it's kept for editing
+        let source_code = read_source(None)?;
+        let lang = match lang {
+            Some(lang) => lang,
+            None => registry.detect(None, &source_code)?,
+        };
+        scripts
+            .compile(lang)
+            .context("Failed to compile [SCRIPT] for standard input")?;
+        inputs.push(Input {
+            file_name: None,
+            source_code: Some(source_code),
+            lang,
+        });
+    }
+    for (file_name, named) in input_files(&paths, &globs("include"), &globs("exclude"))? {
+        let lang = match lang {
+            Some(lang) => lang,
+            None => {
+                // Only the lines with shebang and modelines are read
+                let head_and_tail = read_head_and_tail(&file_name)
+                    .with_context(|| format!("Failed to read {}", file_name.display()))?;
+                match registry.detect(Some(&file_name), &head_and_tail) {
+                    Ok(lang) => lang,
+                    // Files in directories may be in any language, they are skipped
+                    Err(error) if !named => {
+                        eprintln!("warning: skipped file: {}", error);
+                        continue;
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        };
+        scripts
+            .compile(lang)
+            .with_context(|| format!("Failed to compile [SCRIPT] for {}", file_name.display()))?;
+        inputs.push(Input {
+            file_name: Some(file_name),
+            source_code: None,
+            lang,
+        });
+    }
+    let jobs: usize = matches
+        .value_of("jobs")
+        .context("missing `--jobs` argument")?
+        .parse()
+        .context("`--jobs` should be a number")?;
+    if jobs == 0 {
+        return Err(anyhow::format_err!("`--jobs` should be greater than 0"));
+    }
+    let job = Job {
+        scripts,
+        config,
+        encoding,
+        in_place: matches.is_present("in-place"),
+        quiet: matches.is_present("quiet"),
     };
     // Start executing command
-    execute_script(lang, script, &mut source_code)?;
-    match matches.occurrences_of("in-place") {
-        0 => println!("{}", source_code),
-        1 => {
-            // TODO in-place write
-            let filename = match matches.value_of("FILE") {
-                Some(name) => name,
-                None => return Err(anyhow::format_err!("[FILE] not exist")),
-            };
-            let mut file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .open(filename)?;
-            file.write(source_code.as_bytes())?;
+    edit_files(&inputs, &job, jobs, &mut io::stdout().lock())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    #[test]
+    fn test_decode_and_encode() {
+        let latin1 = Some(encoding_rs::WINDOWS_1252);
+        // Bytes are kept as they are without encoding
+        assert_eq!(decode(b"caf\xe9".to_vec(), None).unwrap(), b"caf\xe9");
+        assert_eq!(encode(b"caf\xe9".to_vec(), None).unwrap(), b"caf\xe9");
+        let decoded = decode(b"caf\xe9".to_vec(), latin1).unwrap();
+        assert_eq!(decoded, "caf\u{e9}".as_bytes());
+        assert_eq!(encode(decoded, latin1).unwrap(), b"caf\xe9");
+        // Text out of the encoding can't be written back
+        assert!(encode("caf\u{4e2d}".as_bytes().to_vec(), latin1).is_err());
+        assert!(encode(b"caf\xe9".to_vec(), latin1).is_err());
+        assert!(decode(b"caf\xe9".to_vec(), Some(encoding_rs::UTF_8)).is_err());
+    }
+
+    #[test]
+    fn test_input_files() {
+        let temp_dir = TempDir::new("files");
+        let dir = temp_dir.path();
+        std::fs::create_dir_all(dir.join("src").join("gen")).unwrap();
+        for (file, content) in [
+            (".gitignore", "*.o\n"),
+            ("src/.ignore", "gen/\n"),
+            ("a.c", ""),
+            ("a.o", ""),
+            ("src/b.c", ""),
+            ("src/b.h", ""),
+            ("src/gen/c.c", ""),
+        ] {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        let root = dir.to_str().unwrap();
+        let files = |paths: &[&str], includes: &[&str], excludes: &[&str]| -> Vec<PathBuf> {
+            input_files(paths, includes, excludes)
+                .unwrap()
+                .into_iter()
+                .map(|(file, _)| file.strip_prefix(dir).unwrap().to_path_buf())
+                .collect()
+        };
+        let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+        // Ignored files and hidden files are skipped
+        assert_eq!(
+            files(&[root], &[], &[]),
+            paths(&["a.c", "src/b.c", "src/b.h"])
+        );
+        assert_eq!(files(&[root], &["*.c"], &[]), paths(&["a.c", "src/b.c"]));
+        assert_eq!(files(&[root], &[], &["src"]), paths(&["a.c"]));
+        assert_eq!(
+            files(&[root], &["*.c", "*.h"], &["b.c"]),
+            paths(&["a.c", "src/b.h"])
+        );
+        // Files given directly are kept, and given again by directory are skipped
+        let ignored = dir.join("a.o");
+        let named = format!("{}/./src/b.c", root);
+        assert_eq!(
+            files(&[ignored.to_str().unwrap(), &named, root], &[], &[]),
+            paths(&["a.o", "src/b.c", "a.c", "src/b.h"])
+        );
+        // File found in directory first is named by a later path
+        let named: Vec<bool> = input_files(&[root, &format!("{}/a.c", root)], &[], &[])
+            .unwrap()
+            .into_iter()
+            .map(|(_, named)| named)
+            .collect();
+        assert_eq!(named, [true, false, false]);
+    }
+
+    /// Job to execute `script` on C source code
+    #[cfg(feature = "c")]
+    fn c_job(script: &str, in_place: bool) -> Job {
+        let mut scripts = CompiledScripts::new(parse(script).unwrap());
+        scripts.compile(tree_sitter_c::language()).unwrap();
+        Job {
+            scripts,
+            config: Config::default(),
+            encoding: None,
+            in_place,
+            quiet: true,
+        }
+    }
+
+    #[cfg(feature = "c")]
+    #[test]
+    fn test_edit_files_in_order() {
+        let inputs: Vec<Input> = (0..50)
+            .map(|i| Input {
+                file_name: None,
+                source_code: Some(format!("int a{};\n", i).into_bytes()),
+                lang: tree_sitter_c::language(),
+            })
+            .collect();
+        let expected: String = (0..50).map(|i| format!("a{}\n", i)).collect();
+        let job = c_job("/(identifier) @i/p", false);
+        for jobs in [1, 4, 64] {
+            let mut output = vec![];
+            edit_files(&inputs, &job, jobs, &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), expected);
+        }
+    }
+
+    #[cfg(feature = "c")]
+    #[test]
+    fn test_edit_files_stop_on_error() {
+        let temp_dir = TempDir::new("edit");
+        let dir = temp_dir.path();
+        // `b.c` doesn't exist
+        let inputs: Vec<Input> = ["a.c", "b.c", "c.c"]
+            .iter()
+            .map(|file| Input {
+                file_name: Some(dir.join(file)),
+                source_code: None,
+                lang: tree_sitter_c::language(),
+            })
+            .collect();
+        let job = c_job("/(identifier) @i/p; s/(identifier) @tbr/b/", true);
+        for jobs in [1, 4] {
+            for file in ["a.c", "c.c"] {
+                std::fs::write(dir.join(file), "int a;\n").unwrap();
+            }
+            let mut output = vec![];
+            assert!(edit_files(&inputs, &job, jobs, &mut output).is_err());
+            // Files before the failed one are edited and printed, and the rest are not touched,
+            // even if they are edited by other threads
+            assert_eq!(output, b"a\n");
+            assert_eq!(std::fs::read(dir.join("a.c")).unwrap(), b"int b;\n");
+            assert_eq!(std::fs::read(dir.join("c.c")).unwrap(), b"int a;\n");
         }
-        _ => (),
     }
-    Ok(())
 }
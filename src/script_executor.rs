@@ -1,243 +1,1961 @@
-use std::collections::HashMap;
+use std::{cmp::Reverse, collections::HashMap, ops::Range};
 
 use anyhow::Context;
 use tree_sitter::{InputEdit, Language, Node, Parser, Point, Query, QueryCursor, Tree};
 
-use crate::script_parser::{Address, Options, Script};
+use crate::script_parser::{Address, Line, Options, Script};
 
-/// Execute query based on `query_patten` and `source_code`
-fn execute_query<'a>(
+/// Byte order mark of UTF-8
+const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// How nodes are matched by line address
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LineMatch {
+    /// Node lies within the lines
+    #[default]
+    Within,
+    /// Node intersects the lines
+    Intersect,
+}
+
+/// Which edit is kept when edits of a command overlap
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Conflict {
+    /// Stop with an error listing all conflicts
+    #[default]
+    Error,
+    /// Keep the edit of the outer node
+    Outermost,
+    /// Keep the edit of the inner node
+    Innermost,
+    /// Keep the edit planned first
+    First,
+}
+
+/// Settings of script execution
+#[derive(Default)]
+pub struct Config {
+    pub line_match: LineMatch,
+    pub conflict: Conflict,
+}
+
+/// Compiled queries of scripts in a language
+pub struct Queries {
     lang: Language,
-    query_patten: String,
-    source_code: &String,
+    queries: HashMap<String, Query>,
+}
+
+impl Queries {
+    /// Compile queries of all patterns in `scripts`, including the ones in blocks
+    fn new(lang: Language, scripts: &[Script]) -> anyhow::Result<Queries> {
+        let mut queries = Queries {
+            lang,
+            queries: HashMap::new(),
+        };
+        queries.compile(scripts)?;
+        Ok(queries)
+    }
+
+    fn compile(&mut self, scripts: &[Script]) -> anyhow::Result<()> {
+        for script in scripts {
+            let mut patterns = match &script.address {
+                Some(Address::Pattern(pattern)) => vec![pattern],
+                Some(Address::PatternRange(start, end)) => vec![start, end],
+                _ => vec![],
+            };
+            match &script.options {
+                Some(Options::S { pattern, .. }) => patterns.push(pattern),
+                Some(Options::Block { scripts }) => self.compile(scripts)?,
+                _ => (),
+            }
+            for pattern in patterns {
+                if !self.queries.contains_key(pattern) {
+                    let query = Query::new(self.lang, pattern)
+                        .with_context(|| format!("Failed to parse query {}", pattern))?;
+                    self.queries.insert(pattern.clone(), query);
+                }
+            }
+            // Names selecting captures must be captured by their query
+            match (&script.address, &script.options) {
+                (
+                    _,
+                    Some(Options::S {
+                        placeholder,
+                        assignments,
+                        pattern,
+                        ..
+                    }),
+                ) => {
+                    for name in placeholder
+                        .iter()
+                        .chain(assignments.iter().map(|(name, _)| name))
+                    {
+                        self.check_capture(pattern, name)?;
+                    }
+                }
+                (
+                    Some(Address::Pattern(pattern)),
+                    Some(
+                        Options::A {
+                            placeholder: Some(name),
+                            ..
+                        }
+                        | Options::D {
+                            placeholder: Some(name),
+                        },
+                    ),
+                ) => self.check_capture(pattern, name)?,
+                _ => (),
+            }
+            // Captures referred by replacements must be captured by their query
+            match &script.options {
+                Some(Options::S {
+                    pattern,
+                    replace,
+                    assignments,
+                    ..
+                }) => {
+                    let replaces = assignments.iter().map(|(_, content)| content);
+                    for replace in std::iter::once(replace).chain(replaces) {
+                        for name in capture_references(replace)? {
+                            self.check_capture(pattern, &name)?;
+                        }
+                    }
+                }
+                Some(Options::A { content, .. }) if script.command == 'c' => {
+                    for name in capture_references(content)? {
+                        match (&script.address, script.negated) {
+                            (Some(Address::Pattern(pattern)), false) => {
+                                self.check_capture(pattern, &name)?
+                            }
+                            // Nodes selected otherwise have no captures
+                            _ => {
+                                return Err(anyhow::format_err!(
+                                    "capture @{} of c command needs a pattern address",
+                                    name
+                                ))
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that capture `name` is in query of `query_patten`
+    fn check_capture(&self, query_patten: &str, name: &str) -> anyhow::Result<()> {
+        if !self
+            .get(query_patten)?
+            .capture_names()
+            .iter()
+            .any(|capture| capture == name)
+        {
+            return Err(anyhow::format_err!(
+                "capture @{} not found in query {}",
+                name,
+                query_patten
+            ));
+        }
+        Ok(())
+    }
+
+    /// Get compiled query of `query_patten`
+    fn get(&self, query_patten: &str) -> anyhow::Result<&Query> {
+        self.queries
+            .get(query_patten)
+            .with_context(|| format!("query {} is not compiled", query_patten))
+    }
+}
+
+/// Parsed scripts with their queries compiled for each language, shared by workers
+pub struct CompiledScripts {
+    scripts: Vec<Script>,
+    queries: Vec<Queries>,
+}
+
+impl CompiledScripts {
+    pub fn new(scripts: Vec<Script>) -> CompiledScripts {
+        CompiledScripts {
+            scripts,
+            queries: vec![],
+        }
+    }
+
+    /// Compile queries of scripts in `lang`, they are compiled only once for each language
+    pub fn compile(&mut self, lang: Language) -> anyhow::Result<()> {
+        if self.queries.iter().all(|queries| queries.lang != lang) {
+            self.queries.push(Queries::new(lang, &self.scripts)?);
+        }
+        Ok(())
+    }
+
+    /// Get compiled queries in `lang`
+    fn queries(&self, lang: Language) -> anyhow::Result<&Queries> {
+        self.queries
+            .iter()
+            .find(|queries| queries.lang == lang)
+            .context("queries are not compiled in this language")
+    }
+}
+
+/// Parser and query cursor of a worker, reused to execute script on many files
+pub struct Worker {
+    parser: Parser,
+    cursor: QueryCursor,
+}
+
+impl Default for Worker {
+    fn default() -> Worker {
+        Worker {
+            parser: Parser::new(),
+            cursor: QueryCursor::new(),
+        }
+    }
+}
+
+/// Get byte range of line address' lines, lines are counted from 1
+fn line_range(address: &Address, source_code: &[u8]) -> Option<Range<usize>> {
+    let mut line_starts = line_starts(source_code);
+    // A line terminator at the end doesn't start a new line
+    if line_starts.len() > 1 && line_starts.last() == Some(&source_code.len()) {
+        line_starts.pop();
+    }
+    let last_line = line_starts.len();
+    let line_number = |line: &Line| match line {
+        Line::Number(n) => *n as usize,
+        Line::Last => last_line,
+    };
+    let (start, end) = match address {
+        Address::Single(line) => (line_number(line), line_number(line)),
+        // Like sed, if end is less than start, only one line is matched
+        Address::Range(start, end) => {
+            (line_number(start), line_number(end).max(line_number(start)))
+        }
+        _ => return None,
+    };
+    let start_byte = *line_starts.get(start - 1)?;
+    // Range includes the line terminator of the last line
+    let end_byte = line_starts.get(end).copied().unwrap_or(source_code.len());
+    Some(start_byte..end_byte)
+}
+
+/// Check whether `node` is in `range`
+fn in_range(node: &Node, range: &Range<usize>, line_match: LineMatch) -> bool {
+    match line_match {
+        LineMatch::Within => node.start_byte() >= range.start && node.end_byte() <= range.end,
+        LineMatch::Intersect => node.start_byte() < range.end && node.end_byte() > range.start,
+    }
+}
+
+/// Select the outermost nodes lie within `range`, or intersect `range` without containing
+/// all of it by `LineMatch::Intersect`, and don't overlap `excluded` ranges
+fn select_nodes<'a>(
+    node: Node<'a>,
+    range: &Range<usize>,
+    line_match: LineMatch,
+    excluded: &[Range<usize>],
+    selected: &mut Vec<Node<'a>>,
+) {
+    if excluded
+        .iter()
+        .any(|excluded| in_range(&node, excluded, LineMatch::Within))
+    {
+        return;
+    }
+    let matched = match line_match {
+        LineMatch::Within => in_range(&node, range, LineMatch::Within),
+        // Nodes containing the whole range are split into children, except tokens
+        LineMatch::Intersect => {
+            in_range(&node, range, LineMatch::Intersect)
+                && (node.child_count() == 0
+                    || node.start_byte() > range.start
+                    || node.end_byte() < range.end)
+        }
+    };
+    if matched
+        && !excluded
+            .iter()
+            .any(|excluded| in_range(&node, excluded, LineMatch::Intersect))
+    {
+        selected.push(node);
+        return;
+    }
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if in_range(&child, range, LineMatch::Intersect) {
+            select_nodes(child, range, line_match, excluded, selected);
+        }
+    }
+}
+
+/// Remove the line terminator at the end of `text`
+fn trim_line_ending(text: &[u8]) -> &[u8] {
+    match text.strip_suffix(b"\n") {
+        Some(text) => text.strip_suffix(b"\r").unwrap_or(text),
+        None => text,
+    }
+}
+
+/// Split `nodes` in document order into runs of adjacent nodes, a run ends before any
+/// `excluded` range between two nodes
+fn adjacent_runs<'a, 'b>(nodes: &'b [Node<'a>], excluded: &[Range<usize>]) -> Vec<&'b [Node<'a>]> {
+    let mut runs = vec![];
+    let mut start = 0;
+    for end in 1..nodes.len() {
+        let gap = nodes[end - 1].end_byte()..nodes[end].start_byte();
+        if excluded
+            .iter()
+            .any(|excluded| excluded.start < gap.end && excluded.end > gap.start)
+        {
+            runs.push(&nodes[start..end]);
+            start = end;
+        }
+    }
+    if start < nodes.len() {
+        runs.push(&nodes[start..]);
+    }
+    runs
+}
+
+/// Byte ranges that commands are restricted to, and how nodes are matched by them
+#[derive(Clone)]
+struct Scope {
+    ranges: Vec<Range<usize>>,
+    line_match: LineMatch,
+    /// Nodes inside these ranges are out of scope, used by negated address
+    excluded: Vec<Range<usize>>,
+}
+
+impl Scope {
+    /// Check whether `node` is in `range` of scope and not excluded
+    fn contains(&self, node: &Node, range: &Range<usize>) -> bool {
+        in_range(node, range, self.line_match)
+            && !self
+                .excluded
+                .iter()
+                .any(|excluded| in_range(node, excluded, LineMatch::Within))
+    }
+
+    /// Select the outermost nodes in scope
+    fn select_nodes<'a>(&self, root_node: Node<'a>) -> Vec<Node<'a>> {
+        let mut selected = vec![];
+        for range in &self.ranges {
+            select_nodes(
+                root_node,
+                range,
+                self.line_match,
+                &self.excluded,
+                &mut selected,
+            );
+        }
+        selected
+    }
+}
+
+/// Remove ranges nested in other ranges, the rest are sorted by position
+fn outermost_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| (range.start, Reverse(range.end)));
+    let mut outermost: Vec<Range<usize>> = vec![];
+    for range in ranges {
+        match outermost.last() {
+            Some(last) if range.end <= last.end => (),
+            _ => outermost.push(range),
+        }
+    }
+    outermost
+}
+
+/// Captured nodes of a query match with their capture names
+type Captures<'a> = Vec<(String, Node<'a>)>;
+
+/// Execute query based on `query_patten` and `source_code`, return captures of each match,
+/// only nodes in `scope` are captured if it is given, and matches are grouped by scope's ranges
+fn query_matches<'a>(
+    queries: &Queries,
+    cursor: &mut QueryCursor,
+    query_patten: &str,
+    source_code: &[u8],
     root_node: Node<'a>,
-) -> anyhow::Result<HashMap<String, Vec<Node<'a>>>> {
-    let mut cursor = QueryCursor::new();
-    let query = Query::new(lang, &query_patten).context("Failed to parse query")?;
+    scope: Option<&Scope>,
+) -> anyhow::Result<Vec<Vec<Captures<'a>>>> {
+    let query = queries.get(query_patten)?;
     let capture_names = query.capture_names();
-    let mut node_map: HashMap<String, Vec<Node>> = HashMap::new();
-    for m in cursor.matches(&query, root_node, source_code.as_bytes()) {
-        for c in m.captures {
-            let matched_node = c.node;
-            // Insert capture name and position into table
-            let entry = node_map
-                .entry(
-                    capture_names
-                        .get(c.index as usize)
-                        .context(format!("cannot get name from index, {}", c.index))?
-                        .to_string(),
-                )
-                .or_insert(vec![]);
-            entry.push(matched_node);
+    let ranges = match scope {
+        Some(scope) => scope.ranges.clone(),
+        None => vec![root_node.byte_range()],
+    };
+    let mut groups = vec![];
+    for range in ranges {
+        let mut matches = vec![];
+        cursor.set_byte_range(range.clone());
+        for m in cursor.matches(query, root_node, source_code) {
+            let mut captures = vec![];
+            for c in m.captures {
+                let matched_node = c.node;
+                if let Some(scope) = scope {
+                    if !scope.contains(&matched_node, &range) {
+                        continue;
+                    }
+                }
+                let name = capture_names
+                    .get(c.index as usize)
+                    .context(format!("cannot get name from index, {}", c.index))?;
+                captures.push((name.to_string(), matched_node));
+            }
+            if !captures.is_empty() {
+                matches.push(captures);
+            }
+        }
+        groups.push(matches);
+    }
+    Ok(groups)
+}
+
+/// Execute query based on `query_patten` and `source_code`, return nodes of all captures
+/// in document order, only nodes in `scope` are captured if it is given
+fn execute_query<'a>(
+    queries: &Queries,
+    cursor: &mut QueryCursor,
+    query_patten: &str,
+    source_code: &[u8],
+    root_node: Node<'a>,
+    scope: Option<&Scope>,
+) -> anyhow::Result<Vec<Node<'a>>> {
+    let groups = query_matches(queries, cursor, query_patten, source_code, root_node, scope)?;
+    let mut nodes: Vec<Node> = groups
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|(_, node)| node)
+        .collect();
+    // Outer node goes first if nodes start at the same position
+    nodes.sort_by_key(|node| (node.start_byte(), Reverse(node.end_byte())));
+    nodes.dedup();
+    Ok(nodes)
+}
+
+/// Select the capture named `placeholder` in each match, or the outermost capture if not given,
+/// the selected nodes with captures of their matches are in document order without duplication
+fn select_captures<'a>(
+    groups: Vec<Vec<Captures<'a>>>,
+    placeholder: Option<&str>,
+) -> Vec<(Node<'a>, Captures<'a>)> {
+    let mut selected: Vec<(Node, Captures)> = groups
+        .into_iter()
+        .flatten()
+        .filter_map(|captures| {
+            let mut nodes = captures
+                .iter()
+                .filter_map(|(name, node)| match placeholder {
+                    Some(placeholder) if name != placeholder => None,
+                    _ => Some(*node),
+                });
+            let node = match placeholder {
+                Some(_) => nodes.next(),
+                None => nodes.min_by_key(|node| (node.start_byte(), Reverse(node.end_byte()))),
+            }?;
+            Some((node, captures))
+        })
+        .collect();
+    selected.sort_by_key(|(node, _)| (node.start_byte(), Reverse(node.end_byte())));
+    selected.dedup_by_key(|(node, _)| node.byte_range());
+    selected
+}
+
+/// Select the `occurrence`th match capturing any of `targets` in each group of matches,
+/// or all matches since the `occurrence`th one if `global`
+fn select_occurrence<'a>(
+    groups: Vec<Vec<Captures<'a>>>,
+    targets: &[&str],
+    occurrence: usize,
+    global: bool,
+) -> Vec<Captures<'a>> {
+    let mut selected = vec![];
+    for matches in groups {
+        // Count matches in document order of their first target
+        let mut matches: Vec<(Node, Captures)> = matches
+            .into_iter()
+            .filter_map(|captures| {
+                let node = captures
+                    .iter()
+                    .filter(|(name, _)| targets.contains(&name.as_str()))
+                    .map(|(_, node)| *node)
+                    .min_by_key(|node| (node.start_byte(), Reverse(node.end_byte())))?;
+                Some((node, captures))
+            })
+            .collect();
+        matches.sort_by_key(|(node, _)| (node.start_byte(), Reverse(node.end_byte())));
+        selected.extend(
+            matches
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| {
+                    if global {
+                        i + 1 >= occurrence
+                    } else {
+                        i + 1 == occurrence
+                    }
+                })
+                .map(|(_, (_, captures))| captures),
+        );
+    }
+    selected
+}
+
+/// Part of a replacement
+enum Piece {
+    /// Literal bytes
+    Text(Vec<u8>),
+    /// `&`, text of the replaced range
+    Replaced,
+    /// `${name}`, text of capture `name`
+    Capture(String),
+}
+
+/// Split `replace` into literal text, `&` and `${name}`, `\&`, `\$` and `\\` are literal characters
+fn replacement_pieces(replace: &str) -> anyhow::Result<Vec<Piece>> {
+    let mut pieces = vec![];
+    let mut literal = vec![];
+    // Special characters are ASCII, which never appear in other UTF-8 characters
+    let mut bytes = replace.bytes().peekable();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => match bytes.peek() {
+                Some(&escaped @ (b'&' | b'$' | b'\\')) => {
+                    literal.push(escaped);
+                    bytes.next();
+                }
+                _ => literal.push(byte),
+            },
+            b'&' => {
+                pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                pieces.push(Piece::Replaced);
+            }
+            b'$' if bytes.peek() == Some(&b'{') => {
+                bytes.next();
+                let mut name = vec![];
+                loop {
+                    match bytes.next() {
+                        Some(b'}') => break,
+                        Some(b) => name.push(b),
+                        None => {
+                            return Err(anyhow::format_err!(
+                                "missing '}}' after ${{{}",
+                                String::from_utf8_lossy(&name)
+                            ))
+                        }
+                    }
+                }
+                pieces.push(Piece::Text(std::mem::take(&mut literal)));
+                pieces.push(Piece::Capture(String::from_utf8_lossy(&name).into_owned()));
+            }
+            _ => literal.push(byte),
+        }
+    }
+    pieces.push(Piece::Text(literal));
+    Ok(pieces)
+}
+
+/// Names of captures referred by `${name}` in `replace`
+fn capture_references(replace: &str) -> anyhow::Result<Vec<String>> {
+    Ok(replacement_pieces(replace)?
+        .into_iter()
+        .filter_map(|piece| match piece {
+            Piece::Capture(name) => Some(name),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Expand `${name}` in `replace` to text of capture `name` in the same match,
+/// and `&` to text of the `replaced` range, `\&`, `\$` and `\\` are literal characters.
+/// A capture of the query not captured in the match, e.g. an optional one, expands to nothing
+fn expand_replacement(
+    replace: &str,
+    replaced: &Range<usize>,
+    captures: &Captures,
+    source_code: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let text = |range: Range<usize>| source_code.get(range).context("get range fail");
+    let mut expanded = vec![];
+    for piece in replacement_pieces(replace)? {
+        match piece {
+            Piece::Text(literal) => expanded.extend(literal),
+            Piece::Replaced => expanded.extend_from_slice(text(replaced.clone())?),
+            Piece::Capture(name) => {
+                let captured = captures
+                    .iter()
+                    .find(|(capture_name, _)| *capture_name == name);
+                if let Some((_, captured)) = captured {
+                    expanded.extend_from_slice(text(captured.byte_range())?);
+                }
+            }
         }
     }
-    Ok(node_map)
+    Ok(expanded)
+}
+
+/// Get byte ranges from the first node matched by `start` to the next node matched by `end`
+/// inside `within`, if no node matched by `end`, the range lasts until the end of `within`
+fn pattern_ranges(
+    queries: &Queries,
+    cursor: &mut QueryCursor,
+    start: &str,
+    end: &str,
+    source_code: &[u8],
+    root_node: Node,
+    within: Scope,
+) -> anyhow::Result<Vec<Range<usize>>> {
+    let start_nodes = execute_query(
+        queries,
+        cursor,
+        start,
+        source_code,
+        root_node,
+        Some(&within),
+    )?;
+    let end_nodes = execute_query(queries, cursor, end, source_code, root_node, Some(&within))?;
+    let mut ranges = vec![];
+    for range in &within.ranges {
+        let mut pos = range.start;
+        while let Some(first) = start_nodes
+            .iter()
+            .find(|node| node.start_byte() >= pos && node.end_byte() <= range.end)
+        {
+            match end_nodes
+                .iter()
+                .find(|node| node.start_byte() >= first.end_byte() && node.end_byte() <= range.end)
+            {
+                Some(last) => {
+                    ranges.push(first.start_byte()..last.end_byte());
+                    pos = last.end_byte().max(pos + 1);
+                }
+                None => {
+                    ranges.push(first.start_byte()..range.end);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+/// Shift `range` to its new position after `edit`,
+/// content inserted at the boundary of `range` is not included
+fn edit_range(range: &mut Range<usize>, edit: &InputEdit) {
+    if range.start >= edit.old_end_byte {
+        range.start = range.start - edit.old_end_byte + edit.new_end_byte;
+    } else if range.start > edit.start_byte {
+        range.start = edit.new_end_byte;
+    }
+    if range.end > edit.old_end_byte {
+        range.end = range.end - edit.old_end_byte + edit.new_end_byte;
+    } else if range.end > edit.start_byte {
+        range.end = edit.new_end_byte.max(range.start);
+    }
+}
+
+/// Command and node that an edit comes from
+#[derive(Clone, Debug)]
+struct Origin {
+    command: char,
+    /// Capture name of the node, empty if the node isn't selected by capture name
+    capture: String,
+    /// Start position of the node
+    position: Point,
+}
+
+impl Origin {
+    fn new(command: char, capture: &str, node: &Node) -> Origin {
+        Origin {
+            command,
+            capture: capture.to_string(),
+            position: node.start_position(),
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "`{}`", self.command)?;
+        if !self.capture.is_empty() {
+            write!(f, " @{}", self.capture)?;
+        }
+        // Rows and columns of tree-sitter start from 0
+        write!(
+            f,
+            " at {}:{}",
+            self.position.row + 1,
+            self.position.column + 1
+        )
+    }
+}
+
+/// Replace `byte_range` of source code with `replacement`
+#[derive(Clone, Debug)]
+struct Edit {
+    byte_range: Range<usize>,
+    replacement: Vec<u8>,
+    origin: Origin,
+    /// Query match that the edit is planned for, edits of a match are kept or dropped together
+    match_id: Option<usize>,
+}
+
+/// Byte offset where each line starts
+fn line_starts(source_code: &[u8]) -> Vec<usize> {
+    let mut line_starts = vec![0];
+    line_starts.extend(
+        source_code
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .map(|(i, _)| i + 1),
+    );
+    line_starts
+}
+
+/// Get position of `byte` from byte offsets where lines start
+fn byte_position(line_starts: &[usize], byte: usize) -> Point {
+    let row = line_starts.partition_point(|&start| start <= byte) - 1;
+    Point::new(row, byte - line_starts[row])
+}
+
+/// Get the position after `text` which starts at `start`, columns are counted in bytes
+fn end_position(start: Point, text: &[u8]) -> Point {
+    match text.iter().rposition(|byte| *byte == b'\n') {
+        Some(last) => Point::new(
+            start.row + text.iter().filter(|byte| **byte == b'\n').count(),
+            text.len() - last - 1,
+        ),
+        None => Point::new(start.row, start.column + text.len()),
+    }
 }
 
 /// Calculate edit position
-fn calculate_edit(node: &Node, value: &String) -> InputEdit {
-    let start_byte = node.start_byte();
-    let new_end_byte = start_byte + value.len();
-    let start_position = node.start_position();
-    let new_end_position = Point::new(start_position.row, start_position.column + value.len());
+fn calculate_edit(edit: &Edit, line_starts: &[usize]) -> InputEdit {
+    let start_byte = edit.byte_range.start;
+    let start_position = byte_position(line_starts, start_byte);
     InputEdit {
         start_byte,
-        old_end_byte: node.end_byte(),
-        new_end_byte,
+        old_end_byte: edit.byte_range.end,
+        new_end_byte: start_byte + edit.replacement.len(),
         start_position,
-        old_end_position: node.end_position(),
-        new_end_position,
+        old_end_position: byte_position(line_starts, edit.byte_range.end),
+        new_end_position: end_position(start_position, &edit.replacement),
     }
 }
 
-/// Replace source code with `replace_table`
-fn replace_source(
-    tree: Tree,
-    parser: &mut Parser,
-    node_map: &mut HashMap<String, Vec<Node>>,
-    source_code: &mut String,
-    replace_table: HashMap<String, String>,
-) -> anyhow::Result<()> {
-    let mut edit_tree = tree;
-    let mut all_edit: Vec<InputEdit> = Vec::new();
-    for (name, value) in replace_table.iter() {
-        let nodes = node_map
-            .get_mut(name)
-            .context(format!("Cannot get name {}", name))?;
-        for node in nodes.iter_mut() {
-            // Edit all node to its new position
-            for edit in &all_edit {
-                node.edit(edit);
-            }
-            // Replace in source code
-            // end_byte points to tail + 1
-            source_code.replace_range(node.start_byte()..node.end_byte(), value);
-            let input_edit = calculate_edit(node, value);
-            all_edit.push(input_edit);
-            // Edit and parse after modifying source code
-            edit_tree.edit(&input_edit);
-            edit_tree = parser
-                .parse(&source_code, Some(&edit_tree))
-                .context("Re-generate tree fail")?;
+/// Keep edits not overlapped by `policy`, `edits` are sorted by position and paired with the
+/// order they are planned. Return the kept edits, each dropped edit is pushed to `dropped` with
+/// the origin of the edit it overlaps
+fn keep_edits(
+    edits: Vec<(usize, Edit)>,
+    policy: Conflict,
+    dropped: &mut Vec<(Edit, Origin)>,
+) -> Vec<(usize, Edit)> {
+    // Whether `edit` should be kept instead of the overlapped `kept` edit
+    let wins = |(order, edit): &(usize, Edit), (kept_order, kept): &(usize, Edit)| {
+        let (len, kept_len) = (edit.byte_range.len(), kept.byte_range.len());
+        match policy {
+            Conflict::Outermost if len != kept_len => len > kept_len,
+            Conflict::Innermost if len != kept_len => len < kept_len,
+            _ => order < kept_order,
+        }
+    };
+    // Whether deleting `outer` deletes `inner` too, like deleting a node and its descendant
+    let deletes = |outer: &Edit, inner: &Edit| {
+        outer.replacement.is_empty()
+            && inner.replacement.is_empty()
+            && outer.byte_range.start <= inner.byte_range.start
+            && inner.byte_range.end <= outer.byte_range.end
+    };
+    let mut kept: Vec<(usize, Edit)> = vec![];
+    for edit in edits {
+        // Kept edits don't overlap each other, so the overlapped ones are at the end
+        let overlapped = kept
+            .iter()
+            .rposition(|(_, kept)| kept.byte_range.end <= edit.1.byte_range.start)
+            .map_or(0, |i| i + 1);
+        if kept[overlapped..].iter().any(|(_, kept)| {
+            (kept.byte_range == edit.1.byte_range && kept.replacement == edit.1.replacement)
+                || deletes(kept, &edit.1)
+        }) {
+            continue;
+        }
+        let rest: Vec<(usize, Edit)> = kept
+            .drain(overlapped..)
+            .filter(|(_, kept)| !deletes(&edit.1, kept))
+            .collect();
+        kept.extend(rest);
+        match kept[overlapped..].iter().find(|kept| !wins(&edit, kept)) {
+            Some((_, winner)) => {
+                let origin = winner.origin.clone();
+                dropped.push((edit.1, origin));
+            }
+            None => {
+                for (_, loser) in kept.drain(overlapped..) {
+                    dropped.push((loser, edit.1.origin.clone()));
+                }
+                kept.push(edit);
+            }
         }
     }
-    Ok(())
+    kept
 }
 
-/// Delete matched node in source code
-fn delete_node(
-    tree: Tree,
-    parser: &mut Parser,
-    node_map: &mut HashMap<String, Vec<Node>>,
-    source_code: &mut String,
-) -> anyhow::Result<()> {
-    let mut edit_tree = tree;
-    let mut all_edit: Vec<InputEdit> = Vec::new();
-    let empty_str = String::from("");
-    for (_, nodes) in node_map.iter_mut() {
-        for node in nodes {
-            for edit in &all_edit {
-                node.edit(edit);
-            }
-            source_code.replace_range(node.start_byte()..node.end_byte(), &empty_str);
-            let input_edit = calculate_edit(node, &empty_str);
-            all_edit.push(input_edit);
-            // Edit and parse after modifying source code
-            edit_tree.edit(&input_edit);
-            edit_tree = parser
-                .parse(&source_code, Some(&edit_tree))
-                .context("Re-generate tree fail")?;
+/// Resolve overlapped edits by `policy`, `edits` are sorted by position and paired with the
+/// order they are planned. Edits touching each other, or inserting at the same position
+/// are not overlapped, duplicated edits are applied once, and deletions inside a deleted range
+/// are dropped without conflict. When an edit of a query match is dropped, the other edits of
+/// the match are dropped too, so a match is never rewritten partly
+fn resolve_conflicts(edits: Vec<(usize, Edit)>, policy: Conflict) -> anyhow::Result<Vec<Edit>> {
+    let mut dropped_matches = vec![];
+    // Each conflict is reported as the dropped edit overlaps the kept one
+    let mut conflicts = vec![];
+    let kept = loop {
+        let remaining = edits
+            .iter()
+            .filter(|(_, edit)| !matches!(edit.match_id, Some(id) if dropped_matches.contains(&id)))
+            .cloned()
+            .collect();
+        let mut dropped = vec![];
+        let kept = keep_edits(remaining, policy, &mut dropped);
+        let mut new_dropped = false;
+        for (loser, winner) in &dropped {
+            if let Some(id) = loser.match_id {
+                if !dropped_matches.contains(&id) {
+                    dropped_matches.push(id);
+                    conflicts.push(format!(
+                        "{} overlaps {} with its match",
+                        loser.origin, winner
+                    ));
+                    new_dropped = true;
+                }
+            }
+        }
+        // Edits overlapped by the dropped matches are resolved again without them
+        if !new_dropped {
+            for (loser, winner) in dropped {
+                conflicts.push(format!("{} overlaps {}", loser.origin, winner));
+            }
+            break kept;
         }
+    };
+    if policy == Conflict::Error && !conflicts.is_empty() {
+        return Err(anyhow::format_err!(
+            "conflicting edits:\n{}",
+            conflicts.join("\n")
+        ));
     }
-    Ok(())
+    for conflict in conflicts {
+        eprintln!("warning: dropped conflicting edit: {}", conflict);
+    }
+    Ok(kept.into_iter().map(|(_, edit)| edit).collect())
 }
 
-/// Append or insert content
-fn append_content(
-    tree: Tree,
+/// Apply planned `edits` to source code from back to front, then parse the edited source code once.
+/// Return the new tree and the applied edits in order.
+/// Edits at the same position are applied in the order they are planned
+fn apply_edits(
+    tree: &Tree,
     parser: &mut Parser,
-    node_map: &mut HashMap<String, Vec<Node>>,
-    source_code: &mut String,
-    content: String,
+    edits: Vec<Edit>,
+    source_code: &mut Vec<u8>,
+    conflict: Conflict,
+) -> anyhow::Result<(Tree, Vec<InputEdit>)> {
+    if edits.is_empty() {
+        return Ok((tree.clone(), vec![]));
+    }
+    let mut edits: Vec<(usize, Edit)> = edits.into_iter().enumerate().collect();
+    edits.sort_by_key(|(order, edit)| (edit.byte_range.start, edit.byte_range.end, *order));
+    let edits = resolve_conflicts(edits, conflict)?;
+    // Editing from back to front keeps the position of edits before unchanged
+    let line_starts = line_starts(source_code);
+    let mut edit_tree = tree.clone();
+    let mut input_edits = vec![];
+    for edit in edits.iter().rev() {
+        let input_edit = calculate_edit(edit, &line_starts);
+        source_code.splice(edit.byte_range.clone(), edit.replacement.iter().copied());
+        edit_tree.edit(&input_edit);
+        input_edits.push(input_edit);
+    }
+    let edit_tree = parser
+        .parse(&source_code, Some(&edit_tree))
+        .context("Re-generate tree fail")?;
+    Ok((edit_tree, input_edits))
+}
+
+/// Plan to delete matched node in source code
+fn delete_node(command: char, capture: &str, nodes: &[Node]) -> Vec<Edit> {
+    nodes
+        .iter()
+        .map(|node| Edit {
+            byte_range: node.byte_range(),
+            replacement: vec![],
+            origin: Origin::new(command, capture, node),
+            match_id: None,
+        })
+        .collect()
+}
+
+/// Plan to append or insert content
+fn append_content(
+    command: char,
+    capture: &str,
+    nodes: &[Node],
+    content: &str,
     is_insert: bool,
-) -> anyhow::Result<()> {
-    let mut edit_tree = tree;
-    let mut all_edit = vec![];
-    for nodes in node_map.values_mut() {
-        for node in nodes {
-            for edit in &all_edit {
-                node.edit(edit);
-            }
-            // Modify position depends on insert or append data
-            let (mod_start_byte, mod_start_pos) = if is_insert == true {
-                (node.start_byte(), node.start_position())
-            } else {
-                (node.end_byte(), node.end_position())
-            };
-            source_code.insert_str(mod_start_byte, &content);
-            let input_edit = InputEdit {
-                start_byte: mod_start_byte,
-                old_end_byte: mod_start_byte,
-                new_end_byte: mod_start_byte + content.len(),
-                start_position: mod_start_pos,
-                old_end_position: mod_start_pos,
-                new_end_position: Point {
-                    row: mod_start_pos.row,
-                    column: mod_start_pos.row + content.len(),
-                },
-            };
-            all_edit.push(input_edit);
-            edit_tree.edit(&input_edit);
-            edit_tree = parser
-                .parse(&source_code, Some(&edit_tree))
-                .context("Re-generate tree fail")?;
-        }
+) -> Vec<Edit> {
+    let mut edits = vec![];
+    for node in nodes {
+        // Modify position depends on insert or append data
+        let position = if is_insert {
+            node.start_byte()
+        } else {
+            node.end_byte()
+        };
+        edits.push(Edit {
+            byte_range: position..position,
+            replacement: content.as_bytes().to_vec(),
+            origin: Origin::new(command, capture, node),
+            match_id: None,
+        });
     }
-    Ok(())
+    edits
 }
 
-/// Print matched node
+/// Print matched node to `output`, each node is ended by `line_ending` instead of the line
+/// terminator it ends with, e.g. a preprocessor directive's
 fn print_node(
-    node_map: &mut HashMap<String, Vec<Node>>,
-    source_code: &mut String,
+    nodes: &[Node],
+    source_code: &[u8],
+    line_ending: &str,
+    output: &mut Vec<u8>,
 ) -> anyhow::Result<()> {
-    let mut print_content: Vec<&str> = vec![];
-    for nodes in node_map.values() {
-        for node in nodes {
-            let matched = source_code
-                .get(node.start_byte()..node.end_byte())
-                .context("get range fail")?;
-            print_content.push(matched);
-        }
+    for node in nodes {
+        let matched = source_code
+            .get(node.start_byte()..node.end_byte())
+            .context("get range fail")?;
+        output.extend_from_slice(trim_line_ending(matched));
+        output.extend_from_slice(line_ending.as_bytes());
     }
-    *source_code = print_content.join("\n");
     Ok(())
 }
 
-/// Get script's ast and execute command in script
+/// State of script execution
+struct Executor<'a> {
+    queries: &'a Queries,
+    worker: &'a mut Worker,
+    tree: Tree,
+    source_code: &'a mut Vec<u8>,
+    config: &'a Config,
+    /// Scopes of enclosing blocks, the innermost one is the last
+    scopes: Vec<Scope>,
+    /// Line terminator of source code, used by content from script
+    line_ending: &'static str,
+    /// Content printed by `p` command, separated from the edited source code
+    output: Vec<u8>,
+}
+
+impl Executor<'_> {
+    /// Convert line terminators in `text` to the source code's one
+    fn convert_line_ending(&self, text: &str) -> String {
+        text.replace("\r\n", "\n").replace('\n', self.line_ending)
+    }
+
+    /// Get the innermost scope, or the whole source code
+    fn current_scope(&self) -> Scope {
+        self.scopes.last().cloned().unwrap_or_else(|| Scope {
+            ranges: std::iter::once(0..self.source_code.len()).collect(),
+            line_match: LineMatch::Within,
+            excluded: vec![],
+        })
+    }
+
+    /// Get the scope selected by `address` inside the innermost scope,
+    /// a `negated` scope is the innermost scope excluding nodes selected by `address`
+    fn address_scope(&mut self, address: &Address, negated: bool) -> anyhow::Result<Scope> {
+        let current_scope = self.current_scope();
+        let root_node = self.tree.root_node();
+        let (ranges, line_match) = match address {
+            Address::Pattern(pattern) => {
+                let nodes = execute_query(
+                    self.queries,
+                    &mut self.worker.cursor,
+                    pattern,
+                    self.source_code,
+                    root_node,
+                    self.scopes.last(),
+                )?;
+                let ranges = nodes.iter().map(Node::byte_range).collect();
+                (outermost_ranges(ranges), LineMatch::Within)
+            }
+            Address::PatternRange(start, end) => (
+                pattern_ranges(
+                    self.queries,
+                    &mut self.worker.cursor,
+                    start,
+                    end,
+                    self.source_code,
+                    root_node,
+                    current_scope.clone(),
+                )?,
+                LineMatch::Within,
+            ),
+            Address::Single(_) | Address::Range(_, _) => {
+                let lines = line_range(address, self.source_code);
+                let ranges = current_scope
+                    .ranges
+                    .iter()
+                    .filter_map(|range| {
+                        let lines = lines.as_ref()?;
+                        let intersection = range.start.max(lines.start)..range.end.min(lines.end);
+                        (intersection.start < intersection.end).then_some(intersection)
+                    })
+                    .collect();
+                (ranges, self.config.line_match)
+            }
+        };
+        if negated {
+            let mut excluded = current_scope.excluded;
+            excluded.extend(ranges);
+            return Ok(Scope {
+                excluded,
+                ..current_scope
+            });
+        }
+        Ok(Scope {
+            ranges,
+            line_match,
+            excluded: current_scope.excluded,
+        })
+    }
+
+    /// Shift ranges of all scopes after source code is edited
+    fn edit_scopes(&mut self, edits: &[InputEdit]) {
+        for scope in self.scopes.iter_mut() {
+            for range in scope.ranges.iter_mut().chain(scope.excluded.iter_mut()) {
+                for edit in edits {
+                    edit_range(range, edit);
+                }
+            }
+        }
+    }
+
+    /// Execute a single command
+    fn execute_command(&mut self, script: &Script) -> anyhow::Result<()> {
+        let edits = match script.command {
+            's' => {
+                let (placeholder, assignments, pattern, replace, occurrence, global) =
+                    match &script.options {
+                        Some(Options::S {
+                            placeholder,
+                            assignments,
+                            pattern,
+                            replace,
+                            occurrence,
+                            global,
+                        }) => (
+                            placeholder,
+                            assignments,
+                            pattern,
+                            replace,
+                            *occurrence,
+                            *global,
+                        ),
+                        _ => return Err(anyhow::format_err!("missing `s` command's options")),
+                    };
+                // Captures to be replaced and their replacements
+                let mut targets: Vec<(&str, String)> = assignments
+                    .iter()
+                    .map(|(name, content)| (name.as_str(), self.convert_line_ending(content)))
+                    .collect();
+                match placeholder {
+                    Some(placeholder) => {
+                        targets.push((placeholder, self.convert_line_ending(replace)))
+                    }
+                    None if assignments.is_empty() => {
+                        targets.push(("tbr", self.convert_line_ending(replace)))
+                    }
+                    None => (),
+                }
+                // Run the query only inside the scope of address or enclosing block
+                let scope = match &script.address {
+                    Some(address) => Some(self.address_scope(address, script.negated)?),
+                    None => None,
+                };
+                let groups = query_matches(
+                    self.queries,
+                    &mut self.worker.cursor,
+                    pattern,
+                    self.source_code,
+                    self.tree.root_node(),
+                    scope.as_ref().or_else(|| self.scopes.last()),
+                )?;
+                // Occurrences are counted in each range of scope
+                let names: Vec<&str> = targets.iter().map(|(name, _)| *name).collect();
+                let selected = select_occurrence(groups, &names, occurrence as usize, global);
+                let mut edits = vec![];
+                for (match_id, captures) in selected.into_iter().enumerate() {
+                    for (name, replace) in &targets {
+                        let node = captures
+                            .iter()
+                            .find(|(capture_name, _)| capture_name == name)
+                            .map(|(_, node)| *node);
+                        if let Some(node) = node {
+                            let value = expand_replacement(
+                                replace,
+                                &node.byte_range(),
+                                &captures,
+                                self.source_code,
+                            )?;
+                            edits.push(Edit {
+                                byte_range: node.byte_range(),
+                                replacement: value,
+                                origin: Origin::new('s', name, &node),
+                                match_id: Some(match_id),
+                            });
+                        }
+                    }
+                }
+                edits
+            }
+            cmd @ ('d' | 'p' | 'a' | 'i' | 'c') => {
+                let scope = match &script.address {
+                    Some(Address::Pattern(_)) if !script.negated => None,
+                    Some(address) => Some(self.address_scope(address, script.negated)?),
+                    None => match self.scopes.last() {
+                        Some(_) => Some(self.current_scope()),
+                        None => {
+                            return Err(anyhow::format_err!("missing address in {} command", cmd))
+                        }
+                    },
+                };
+                let placeholder = match &script.options {
+                    Some(Options::A { placeholder, .. } | Options::D { placeholder }) => {
+                        placeholder.as_deref()
+                    }
+                    _ => None,
+                };
+                let nodes = match (&script.address, &scope) {
+                    (_, Some(_)) if placeholder.is_some() => {
+                        return Err(anyhow::format_err!(
+                            "placeholder of {} command needs a pattern address",
+                            cmd
+                        ))
+                    }
+                    (_, Some(scope)) => scope
+                        .select_nodes(self.tree.root_node())
+                        .into_iter()
+                        .map(|node| (node, vec![]))
+                        .collect(),
+                    (Some(Address::Pattern(p)), None) => {
+                        let groups = query_matches(
+                            self.queries,
+                            &mut self.worker.cursor,
+                            p,
+                            self.source_code,
+                            self.tree.root_node(),
+                            self.scopes.last(),
+                        )?;
+                        select_captures(groups, placeholder)
+                    }
+                    _ => return Err(anyhow::format_err!("missing address in {} command", cmd)),
+                };
+                let content = match &script.options {
+                    Some(Options::A { content, .. }) => Some(self.convert_line_ending(content)),
+                    _ => None,
+                };
+                if cmd == 'c' {
+                    let content = &content.context("missing content in c command")?;
+                    // Nodes selected by lines or pattern range are replaced at once in each range,
+                    // except excluded nodes between them, and nodes matched by query are replaced
+                    // by their own match's expansion
+                    let spans: Vec<(Range<usize>, Node, Captures)> = match &scope {
+                        Some(scope) if !script.negated => {
+                            let mut spans = vec![];
+                            for range in &scope.ranges {
+                                let range_scope = Scope {
+                                    ranges: vec![range.clone()],
+                                    ..scope.clone()
+                                };
+                                let nodes = range_scope.select_nodes(self.tree.root_node());
+                                for run in adjacent_runs(&nodes, &scope.excluded) {
+                                    let (first, last) = (run[0], run[run.len() - 1]);
+                                    let span = first.start_byte()..last.end_byte();
+                                    spans.push((span, first, vec![]));
+                                }
+                            }
+                            spans
+                        }
+                        _ => nodes
+                            .into_iter()
+                            .map(|(node, captures)| (node.byte_range(), node, captures))
+                            .collect(),
+                    };
+                    let mut edits = vec![];
+                    for (span, node, captures) in spans {
+                        // Line terminator ending the last node is kept, e.g. the root node's
+                        // or a preprocessor directive's
+                        let text = trim_line_ending(&self.source_code[span.clone()]);
+                        let byte_range = span.start..span.start + text.len();
+                        let value =
+                            expand_replacement(content, &byte_range, &captures, self.source_code)?;
+                        edits.push(Edit {
+                            byte_range,
+                            replacement: value,
+                            origin: Origin::new(cmd, placeholder.unwrap_or_default(), &node),
+                            match_id: None,
+                        });
+                    }
+                    edits
+                } else {
+                    // Nodes are in document order, so are the planned edits
+                    let nodes: Vec<Node> = nodes.into_iter().map(|(node, _)| node).collect();
+                    let capture = placeholder.unwrap_or_default();
+                    match cmd {
+                        'd' => delete_node(cmd, capture, &nodes),
+                        'p' => {
+                            print_node(
+                                &nodes,
+                                self.source_code,
+                                self.line_ending,
+                                &mut self.output,
+                            )?;
+                            vec![]
+                        }
+                        _ => {
+                            let content = &content.context("missing content in a command")?;
+                            append_content(cmd, capture, &nodes, content, cmd == 'i')
+                        }
+                    }
+                }
+            }
+            '{' => {
+                let scripts = match &script.options {
+                    Some(Options::Block { scripts }) => scripts,
+                    _ => return Err(anyhow::format_err!("missing commands in block")),
+                };
+                // Commands in block are restricted to the scope of block's address
+                return match &script.address {
+                    Some(address) => {
+                        let scope = self.address_scope(address, script.negated)?;
+                        self.scopes.push(scope);
+                        let result = self.execute_commands(scripts);
+                        self.scopes.pop();
+                        result
+                    }
+                    None => self.execute_commands(scripts),
+                };
+            }
+            other => return Err(anyhow::format_err!("unsupported command: {}", other)),
+        };
+        // Re-generate syntax tree
+        let (new_tree, edits) = apply_edits(
+            &self.tree,
+            &mut self.worker.parser,
+            edits,
+            self.source_code,
+            self.config.conflict,
+        )?;
+        self.tree = new_tree;
+        self.edit_scopes(&edits);
+        Ok(())
+    }
+
+    /// Execute commands in order
+    fn execute_commands(&mut self, scripts: &[Script]) -> anyhow::Result<()> {
+        for script in scripts {
+            self.execute_command(script)?;
+        }
+        Ok(())
+    }
+}
+
+/// Detect line terminator of source code by its first line
+fn line_ending(source_code: &[u8]) -> &'static str {
+    match source_code.iter().position(|byte| *byte == b'\n') {
+        Some(end) if source_code[..end].ends_with(b"\r") => "\r\n",
+        _ => "\n",
+    }
+}
+
+/// Get script's ast and execute commands in script, return the content printed by `p` command.
+/// Source code out of edited ranges is kept as it is, including BOM and line terminators
 pub fn execute_script(
+    scripts: &CompiledScripts,
     lang: Language,
-    script: Script,
-    source_code: &mut String,
-) -> anyhow::Result<()> {
+    source_code: &mut Vec<u8>,
+    config: &Config,
+    worker: &mut Worker,
+) -> anyhow::Result<Vec<u8>> {
+    // BOM isn't a part of code, so it's removed while executing and put back later
+    let bom = source_code.starts_with(BOM);
+    if bom {
+        source_code.drain(..BOM.len());
+    }
+    let queries = scripts.queries(lang)?;
     // Init parser
-    let mut parser = Parser::new();
-    parser.set_language(lang)?;
+    worker.parser.set_language(lang)?;
     // Parse code
-    let tree = parser
-        .parse(source_code.clone(), None)
+    let tree = worker
+        .parser
+        .parse(&source_code, None)
         .context("Failed to parse source code")?;
-    let root_node = tree.root_node();
-    match script.command {
-        's' => {
-            let (placeholder, pattern, replace) = match script.options {
-                Some(Options::S {
-                    placeholder,
-                    pattern,
-                    replace,
-                }) => (placeholder, pattern, replace),
-                _ => return Err(anyhow::format_err!("missing `s` command's options")),
-            };
-            let mut node_map = execute_query(lang, pattern, &source_code, root_node)?;
-            // Re-generate syntax tree
-            let mut replace_table: HashMap<String, String> = HashMap::new();
-            let placeholder = placeholder.unwrap_or(String::from("tbr"));
-            replace_table.insert(placeholder, replace);
-            replace_source(
-                tree.clone(),
-                &mut parser,
-                &mut node_map,
-                source_code,
-                replace_table,
-            )?;
-        }
-        cmd @ ('d' | 'p' | 'a' | 'i') => {
-            let pattern = match script.address {
-                Some(Address::Pattern(p)) => p,
-                _ => return Err(anyhow::format_err!("missing pattern in {} command", cmd)),
-            };
-            let mut node_map = execute_query(lang, pattern, &source_code, root_node)?;
-            match cmd {
-                'd' => delete_node(tree.clone(), &mut parser, &mut node_map, source_code)?,
-                'p' => print_node(&mut node_map, source_code)?,
-                'a' | 'i' => {
-                    let content = match script.options {
-                        Some(Options::A { content }) => content,
-                        _ => return Err(anyhow::format_err!("missing content in a command")),
-                    };
-                    append_content(
-                        tree.clone(),
-                        &mut parser,
-                        &mut node_map,
-                        source_code,
-                        content,
-                        if cmd == 'a' { false } else { true },
-                    )?
+    let mut executor = Executor {
+        queries,
+        worker,
+        tree,
+        line_ending: line_ending(source_code),
+        source_code,
+        config,
+        scopes: vec![],
+        output: vec![],
+    };
+    let result = executor.execute_commands(&scripts.scripts);
+    let output = executor.output;
+    if bom {
+        source_code.splice(0..0, BOM.iter().copied());
+    }
+    result.map(|_| output)
+}
+
+#[cfg(all(test, feature = "c"))]
+mod test {
+    use super::*;
+    use crate::script_parser::parse;
+
+    /// Check that positions of all nodes in `tree` are the same as parsing `source_code` again
+    fn assert_positions(tree: &Tree, source_code: &[u8]) {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_c::language()).unwrap();
+        let expected = parser.parse(source_code, None).unwrap();
+        let (mut cursor, mut expected_cursor) = (tree.walk(), expected.walk());
+        loop {
+            let (node, expected_node) = (cursor.node(), expected_cursor.node());
+            assert_eq!(node.kind(), expected_node.kind());
+            assert_eq!(node.byte_range(), expected_node.byte_range());
+            assert_eq!(node.start_position(), expected_node.start_position());
+            assert_eq!(node.end_position(), expected_node.end_position());
+            if cursor.goto_first_child() {
+                assert!(expected_cursor.goto_first_child());
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                assert!(!expected_cursor.goto_next_sibling());
+                if !cursor.goto_parent() {
+                    return;
                 }
-                _ => (),
+                assert!(expected_cursor.goto_parent());
             }
+            assert!(expected_cursor.goto_next_sibling());
         }
-        _ => todo!("More command"),
     }
-    Ok(())
+
+    /// Execute `script` on `source_code` command by command, and check the tree after each one
+    fn execute(script: &str, source_code: &str) -> String {
+        let scripts = parse(script).unwrap();
+        let queries = Queries::new(tree_sitter_c::language(), &scripts).unwrap();
+        let mut worker = Worker::default();
+        let mut source_code = source_code.as_bytes().to_vec();
+        worker.parser.set_language(queries.lang).unwrap();
+        let tree = worker.parser.parse(&source_code, None).unwrap();
+        let config = Config::default();
+        let mut executor = Executor {
+            queries: &queries,
+            worker: &mut worker,
+            tree,
+            source_code: &mut source_code,
+            config: &config,
+            scopes: vec![],
+            line_ending: "\n",
+            output: vec![],
+        };
+        for script in &scripts {
+            executor.execute_command(script).unwrap();
+            assert_positions(&executor.tree, executor.source_code);
+        }
+        String::from_utf8(source_code).unwrap()
+    }
+
+    /// Parse `script` and compile its queries in C
+    fn compile(script: &str) -> CompiledScripts {
+        let mut scripts = CompiledScripts::new(parse(script).unwrap());
+        scripts.compile(tree_sitter_c::language()).unwrap();
+        scripts
+    }
+
+    const SOURCE_CODE: &str = "int main() {\n    foo(1);\n    bar(2);\n    return 0;\n}\n";
+
+    /// Execute `scripts` on bytes of `source_code` by `config`, return the content printed by `p`
+    /// and the edited source code
+    fn execute_bytes(
+        scripts: &CompiledScripts,
+        source_code: &[u8],
+        config: &Config,
+    ) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let mut source_code = source_code.to_vec();
+        let output = execute_script(
+            scripts,
+            tree_sitter_c::language(),
+            &mut source_code,
+            config,
+            &mut Worker::default(),
+        )?;
+        Ok((output, source_code))
+    }
+
+    /// Execute `script` on `source_code` by `line_match`, return the content printed by `p`
+    /// and the edited source code
+    fn execute_lines(script: &str, source_code: &str, line_match: LineMatch) -> (String, String) {
+        let config = Config {
+            line_match,
+            ..Config::default()
+        };
+        let (output, source_code) =
+            execute_bytes(&compile(script), source_code.as_bytes(), &config).unwrap();
+        (
+            String::from_utf8(output).unwrap(),
+            String::from_utf8(source_code).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_line_address() {
+        let source_code = "int a = f(1,\n  2);\nint b = 3;\nint c = 4;\n";
+        let within = |script| execute_lines(script, source_code, LineMatch::Within);
+        let intersect = |script| execute_lines(script, source_code, LineMatch::Intersect);
+        assert_eq!(within("3p").0, "int b = 3;\n");
+        assert_eq!(within("$p").0, "int c = 4;\n");
+        // Only the part of the first declaration on line 2 lies within lines
+        assert_eq!(within("2,3p").0, "2\n)\n;\nint b = 3;\n");
+        assert_eq!(intersect("2,3p").0, "int a = f(1,\n  2);\nint b = 3;\n");
+        assert_eq!(intersect("2,3d").1, "\n\nint c = 4;\n");
+        assert_eq!(
+            within("2,$s/(number_literal) @tbr/0/g").1,
+            "int a = f(1,\n  0);\nint b = 0;\nint c = 0;\n"
+        );
+        // Call expression across lines 1 and 2 is replaced only by intersect
+        let script = "2s/(call_expression) @tbr/g()/";
+        assert_eq!(within(script).1, source_code);
+        assert_eq!(
+            intersect(script).1,
+            "int a = g();\nint b = 3;\nint c = 4;\n"
+        );
+    }
+
+    /// Calls inside and outside functions
+    const FUNCTIONS: &str = "int a = bar(1);\nint foo(int x) {\n    return bar(x, 2) + x;\n}\nint baz(int x) {\n    return bar(x);\n}\n";
+
+    #[test]
+    fn test_address_scope() {
+        let execute = |script| execute_lines(script, FUNCTIONS, LineMatch::Within);
+        // Identifiers are renamed only inside function `foo`
+        let script = "/(function_definition declarator: (function_declarator declarator: (identifier) @n (#eq? @n \"foo\"))) @f/s/((identifier) @tbr (#eq? @tbr \"x\"))/y/g";
+        assert_eq!(
+            execute(script).1,
+            "int a = bar(1);\nint foo(int y) {\n    return bar(y, 2) + y;\n}\nint baz(int x) {\n    return bar(x);\n}\n"
+        );
+        // Without `g`, only the first capture inside each addressed node is replaced
+        assert_eq!(
+            execute("/(function_definition) @f/s/(identifier) @tbr/_/").1,
+            "int a = bar(1);\nint _(int x) {\n    return bar(x, 2) + x;\n}\nint _(int x) {\n    return bar(x);\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_blocks() {
+        let execute = |script| execute_lines(script, FUNCTIONS, LineMatch::Within);
+        // Only arguments of calls inside functions are replaced
+        let script = "/(function_definition) @f/{ /(call_expression) @c/{ s@arg/(argument_list (_) @arg)/0/g } }";
+        assert_eq!(
+            execute(script).1,
+            "int a = bar(1);\nint foo(int x) {\n    return bar(0, 0) + x;\n}\nint baz(int x) {\n    return bar(0);\n}\n"
+        );
+        // Each command in a block runs on all ranges of the block before the next one
+        let script = "/(function_definition) @f/{ /(call_expression) @c/{ /(identifier) @i/p }; /(number_literal) @n/p }";
+        assert_eq!(execute(script).0, "bar\nx\nbar\nx\n2\n");
+        // Line address in a block is restricted to the block's scope
+        assert_eq!(
+            execute("/(return_statement) @r/{ 3,6s/(identifier) @tbr/y/g }").1,
+            "int a = bar(1);\nint foo(int x) {\n    return y(y, 2) + y;\n}\nint baz(int x) {\n    return y(y);\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_negated_address() {
+        let execute = |script| execute_lines(script, FUNCTIONS, LineMatch::Within);
+        // Top-level nodes that don't match are printed
+        assert_eq!(
+            execute("/(function_definition) @f/!p").0,
+            "int a = bar(1);\n"
+        );
+        // Captures inside nodes matched by address are skipped
+        assert_eq!(
+            execute("/(function_definition) @f/!s/(number_literal) @tbr/9/g").1,
+            FUNCTIONS.replace("bar(1)", "bar(9)")
+        );
+        assert_eq!(
+            execute("/(return_statement) @r/!s/(identifier) @tbr/y/g").1,
+            "int y = y(1);\nint y(int y) {\n    return bar(x, 2) + x;\n}\nint y(int y) {\n    return bar(x);\n}\n"
+        );
+        // Negation in a block selects the rest of the block's scope, only return statements are kept
+        assert_eq!(
+            execute("/(function_definition) @f/{ /(return_statement) @r/!d }").1,
+            "int a = bar(1);\n  \n    return bar(x, 2) + x;\n\n  \n    return bar(x);\n\n"
+        );
+    }
+
+    #[test]
+    fn test_expand_replacement() {
+        let pattern = "(call_expression function: (identifier) @f arguments: (_) @a) @call";
+        let scripts = parse(&format!("s@call/{}//", pattern)).unwrap();
+        let queries = Queries::new(tree_sitter_c::language(), &scripts).unwrap();
+        let source_code = b"int x = foo(bar);";
+        let mut parser = Parser::new();
+        parser.set_language(queries.lang).unwrap();
+        let tree = parser.parse(source_code, None).unwrap();
+        let captures = query_matches(
+            &queries,
+            &mut QueryCursor::new(),
+            pattern,
+            source_code,
+            tree.root_node(),
+            None,
+        )
+        .unwrap()
+        .remove(0)
+        .remove(0);
+        let node = captures
+            .iter()
+            .find(|(name, _)| name == "call")
+            .map(|(_, node)| *node)
+            .unwrap();
+        let expand =
+            |replace| expand_replacement(replace, &node.byte_range(), &captures, source_code);
+        assert_eq!(expand("&;").unwrap(), b"foo(bar);");
+        assert_eq!(expand("log_${f}${a}").unwrap(), b"log_foo(bar)");
+        // Escaped special characters are literal, other `\` and `$` are kept
+        assert_eq!(expand("\\&\\${f}\\\\").unwrap(), b"&${f}\\");
+        assert_eq!(expand("\\n$f $").unwrap(), b"\\n$f $");
+        assert!(expand("${f").is_err());
+        // Capture not in the match expands to nothing, captures not in the query are checked
+        // when the query is compiled
+        assert_eq!(expand("${f}${nope};").unwrap(), b"foo;");
+    }
+
+    #[test]
+    fn test_optional_capture() {
+        let source_code = "f(1); h(x);\n";
+        let execute = |script: &str| execute_lines(script, source_code, LineMatch::Within).1;
+        let query = "(call_expression function: (identifier) @f arguments: (argument_list (number_literal)? @n)) @call";
+        assert_eq!(
+            execute(&format!("s@call/{}/${{f}}_${{n}}/g", query)),
+            "f_1; h_;\n"
+        );
+        assert_eq!(execute(&format!("/{}/c ${{f}}${{n}}", query)), "f1; h;\n");
+    }
+
+    #[test]
+    fn test_change_range() {
+        let source_code = "int a = 1;\nint b = 2;\nint c = 3;\nint d = 4;\n";
+        let execute = |script| execute_lines(script, source_code, LineMatch::Within).1;
+        // Nodes in the lines are replaced by the text once
+        assert_eq!(execute("2,3c X"), "int a = 1;\nX\nint d = 4;\n");
+        assert_eq!(
+            execute("2,3c [&]"),
+            "int a = 1;\n[int b = 2;\nint c = 3;]\nint d = 4;\n"
+        );
+        let script =
+            "/((number_literal) @n (#eq? @n \"2\"))/,/((number_literal) @n (#eq? @n \"3\"))/c X";
+        assert_eq!(execute(script), "int a = 1;\nint b = X;\nint d = 4;\n");
+        // Each node matched by query is replaced separately
+        assert_eq!(execute("/(declaration) @d/c X"), "X\nX\nX\nX\n");
+        // Line terminator at the end of the root node or a directive isn't replaced
+        assert_eq!(execute("1,$c X"), "X\n");
+        let execute_in =
+            |script, source_code| execute_lines(script, source_code, LineMatch::Within).1;
+        assert_eq!(execute_in("1,$c X", "int a;\r\nint b;\r\n"), "X\r\n");
+        assert_eq!(
+            execute_in("1c [&]", "#include <a.h>\nint a;\n"),
+            "[#include <a.h>]\nint a;\n"
+        );
+        assert_eq!(
+            execute_in(
+                "/(preproc_include) @p/c #include <b.h>",
+                "#include <a.h>\nint a;\n"
+            ),
+            "#include <b.h>\nint a;\n"
+        );
+        assert_eq!(
+            execute_in(
+                "/(preproc_def) @d/c X",
+                "#define A 1\n#define B 2\nint x;\n"
+            ),
+            "X\nX\nint x;\n"
+        );
+    }
+
+    #[test]
+    fn test_change_range_excluded() {
+        let source_code = "int a = 1;\n// keep\nint b = 2;\nint c = 3;\n";
+        let execute = |script| execute_lines(script, source_code, LineMatch::Within).1;
+        // Nodes excluded by the enclosing block split the range, and are kept
+        let expected = "X\n// keep\nX\n";
+        assert_eq!(execute("/(comment) @c/!{ 1,4c X\n}"), expected);
+        assert_eq!(execute("/(comment) @c/!{ c X\n}"), expected);
+    }
+
+    #[test]
+    fn test_end_position() {
+        assert_eq!(end_position(Point::new(1, 4), b"abc"), Point::new(1, 7));
+        assert_eq!(end_position(Point::new(1, 4), b"a\nbc"), Point::new(2, 2));
+        assert_eq!(end_position(Point::new(1, 4), b"a\n\n"), Point::new(3, 0));
+    }
+
+    #[test]
+    fn test_edit_tree_positions() {
+        let scripts = parse("/(expression_statement) @s/p; /(return_statement) @r/p").unwrap();
+        let queries = Queries::new(tree_sitter_c::language(), &scripts).unwrap();
+        let mut cursor = QueryCursor::new();
+        let mut source_code = SOURCE_CODE.as_bytes().to_vec();
+        let mut parser = Parser::new();
+        parser.set_language(queries.lang).unwrap();
+        let tree = parser.parse(&source_code, None).unwrap();
+        let statements = execute_query(
+            &queries,
+            &mut cursor,
+            "(expression_statement) @s",
+            &source_code,
+            tree.root_node(),
+            None,
+        )
+        .unwrap();
+        let edits = vec![
+            Edit {
+                byte_range: statements[0].byte_range(),
+                replacement: b"foo(\n  1\n);".to_vec(),
+                origin: Origin::new('s', "s", &statements[0]),
+                match_id: None,
+            },
+            Edit {
+                byte_range: statements[1].end_byte()..statements[1].end_byte(),
+                replacement: b"\n    baz();".to_vec(),
+                origin: Origin::new('a', "s", &statements[1]),
+                match_id: None,
+            },
+        ];
+        let (new_tree, input_edits) =
+            apply_edits(&tree, &mut parser, edits, &mut source_code, Conflict::Error).unwrap();
+        assert_positions(&new_tree, &source_code);
+        // Nodes after the edits are shifted to their new positions without parsing again
+        let mut edited_tree = tree.clone();
+        for input_edit in &input_edits {
+            edited_tree.edit(input_edit);
+        }
+        let query = "(return_statement) @r";
+        let old_node = execute_query(
+            &queries,
+            &mut cursor,
+            query,
+            &source_code,
+            edited_tree.root_node(),
+            None,
+        )
+        .unwrap()[0];
+        let new_node = execute_query(
+            &queries,
+            &mut cursor,
+            query,
+            &source_code,
+            new_tree.root_node(),
+            None,
+        )
+        .unwrap()[0];
+        assert_eq!(old_node.byte_range(), new_node.byte_range());
+        assert_eq!(old_node.start_position(), new_node.start_position());
+        assert_eq!(old_node.end_position(), new_node.end_position());
+    }
+
+    /// Plan to replace `byte_range` with `replacement` by command `s`
+    fn plan(byte_range: Range<usize>, replacement: &str) -> Edit {
+        Edit {
+            byte_range,
+            replacement: replacement.as_bytes().to_vec(),
+            origin: Origin {
+                command: 's',
+                capture: String::new(),
+                position: Point::new(0, 0),
+            },
+            match_id: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_edits_order() {
+        let mut parser = Parser::new();
+        parser.set_language(tree_sitter_c::language()).unwrap();
+        let mut source_code = b"int a = 1;".to_vec();
+        let tree = parser.parse(&source_code, None).unwrap();
+        // Edits are planned out of position order, inserts at the same offset keep their order
+        let edits = vec![
+            plan(8..9, "2"),
+            plan(0..0, "long "),
+            plan(4..4, "*"),
+            plan(0..0, "const "),
+            plan(10..10, " // b"),
+            plan(4..4, "const "),
+        ];
+        let (tree, input_edits) =
+            apply_edits(&tree, &mut parser, edits, &mut source_code, Conflict::Error).unwrap();
+        assert_eq!(source_code, b"long const int *const a = 2; // b");
+        assert_eq!(input_edits.len(), 6);
+        assert_positions(&tree, &source_code);
+    }
+
+    /// Resolve conflicts of `edits` in planned order by `policy`, return start and end of kept edits
+    fn resolve(edits: &[Edit], policy: Conflict) -> anyhow::Result<Vec<(usize, usize)>> {
+        let mut edits: Vec<(usize, Edit)> = edits.iter().cloned().enumerate().collect();
+        edits.sort_by_key(|(order, edit)| (edit.byte_range.start, edit.byte_range.end, *order));
+        let kept = resolve_conflicts(edits, policy)?;
+        Ok(kept
+            .into_iter()
+            .map(|edit| (edit.byte_range.start, edit.byte_range.end))
+            .collect())
+    }
+
+    #[test]
+    fn test_resolve_conflicts() {
+        use Conflict::*;
+        // Node is planned before its child
+        let nested = [plan(0..11, "x"), plan(4..10, "y")];
+        assert!(resolve(&nested, Error).is_err());
+        assert_eq!(resolve(&nested, Outermost).unwrap(), [(0, 11)]);
+        assert_eq!(resolve(&nested, Innermost).unwrap(), [(4, 10)]);
+        assert_eq!(resolve(&nested, First).unwrap(), [(0, 11)]);
+        // Insert inside a replaced range is planned first
+        let insert = [plan(6..6, "z"), plan(4..10, "y")];
+        assert!(resolve(&insert, Error).is_err());
+        assert_eq!(resolve(&insert, Outermost).unwrap(), [(4, 10)]);
+        assert_eq!(resolve(&insert, Innermost).unwrap(), [(6, 6)]);
+        assert_eq!(resolve(&insert, First).unwrap(), [(6, 6)]);
+        for policy in [Error, Outermost, Innermost, First] {
+            // The same edit is applied once
+            let same = [plan(4..10, "y"), plan(4..10, "y")];
+            assert_eq!(resolve(&same, policy).unwrap(), [(4, 10)]);
+            // Adjacent edits and inserts at their boundary don't overlap
+            let adjacent = [
+                plan(4..8, "y"),
+                plan(0..4, "x"),
+                plan(4..4, "w"),
+                plan(8..8, "z"),
+            ];
+            assert_eq!(
+                resolve(&adjacent, policy).unwrap(),
+                [(0, 4), (4, 4), (4, 8), (8, 8)]
+            );
+            // Deleting a node deletes its descendants
+            let deleted = [plan(4..10, ""), plan(0..11, ""), plan(5..6, "")];
+            assert_eq!(resolve(&deleted, policy).unwrap(), [(0, 11)]);
+        }
+        // Deletion inside a replaced range is still a conflict
+        assert!(resolve(&[plan(0..11, "x"), plan(4..10, "")], Error).is_err());
+        // Edits of a match are kept or dropped together, like `foo(bar(1))` rewritten by
+        // captures of the function and the arguments
+        let matched = |match_id, byte_range, replacement| Edit {
+            match_id: Some(match_id),
+            ..plan(byte_range, replacement)
+        };
+        let matches = [
+            matched(0, 0..3, "X"),
+            matched(0, 3..11, "()"),
+            matched(1, 4..7, "X"),
+            matched(1, 7..10, "()"),
+        ];
+        assert!(resolve(&matches, Error).is_err());
+        assert_eq!(resolve(&matches, Outermost).unwrap(), [(0, 3), (3, 11)]);
+        assert_eq!(resolve(&matches, Innermost).unwrap(), [(4, 7), (7, 10)]);
+        assert_eq!(resolve(&matches, First).unwrap(), [(0, 3), (3, 11)]);
+        // Edit overlapped by a dropped match is kept
+        let overlapped = [
+            matched(0, 0..3, "X"),
+            matched(0, 3..11, "()"),
+            plan(0..3, "Y"),
+            plan(4..7, "Z"),
+        ];
+        assert_eq!(resolve(&overlapped, Innermost).unwrap(), [(0, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn test_conflicting_matches() {
+        let config = Config {
+            conflict: Conflict::Innermost,
+            ..Config::default()
+        };
+        let script =
+            "s@name=X@args=()/(call_expression function: (_) @name arguments: (_) @args)//g";
+        let (_, result) =
+            execute_bytes(&compile(script), b"int a = foo(bar(1));\n", &config).unwrap();
+        // The outer call is left as it is, instead of rewriting its function only
+        assert_eq!(result, b"int a = foo(X());\n");
+    }
+
+    #[test]
+    fn test_delete_nested_nodes() {
+        let (_, result) = execute_lines(
+            "/(call_expression) @c/d",
+            "int a = foo(bar(1));\n",
+            LineMatch::Within,
+        );
+        assert_eq!(result, "int a = ;\n");
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let source_code: String = (0..50)
+            .map(|i| format!("int v{} = f{}({});\n", i, i, i))
+            .collect();
+        let expected: String = (0..50)
+            .map(|i| format!("int v{} = f{}_log(({}));// done\n", i, i, i))
+            .collect();
+        // Captures of different names in many matches are replaced by one command
+        let script = "s@f=${f}_log@a=(&)/(call_expression function: (_) @f arguments: (argument_list (_) @a))//g\n/(declaration) @d/a // done";
+        // Each run has new hash maps, their order must not change the output
+        for _ in 0..10 {
+            let (_, result) = execute_lines(script, &source_code, LineMatch::Within);
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn test_multi_line_replace() {
+        let result = execute(
+            "s/((identifier) @tbr (#eq? @tbr \"foo\"))/foo(\\\n0)\\\n;\\\n/\n5s/(number_literal) @tbr/9/",
+            SOURCE_CODE,
+        );
+        assert_eq!(
+            result,
+            "int main() {\n    foo(\n0)\n;\n(9);\n    bar(2);\n    return 0;\n}\n"
+        );
+        // Arguments of the first call are moved to line 3 by the replacement
+        let result = execute(
+            "s/((identifier) @tbr (#eq? @tbr \"foo\"))/f\\\n/\n3s/(number_literal) @tbr/9/",
+            SOURCE_CODE,
+        );
+        assert_eq!(
+            result,
+            "int main() {\n    f\n(9);\n    bar(2);\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_append() {
+        let result = execute(
+            "/(expression_statement) @s/a\\\n\\\n    baz();\n4s/(number_literal) @tbr/9/",
+            SOURCE_CODE,
+        );
+        assert_eq!(
+            result,
+            "int main() {\n    foo(1);\n    baz();\n    bar(9);\n    baz();\n    return 0;\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_multi_line_insert() {
+        let result = execute(
+            "/(return_statement) @r/i\\\n// done\\\n    \n/(call_expression) @c/s/(number_literal) @tbr/3/g\n$d",
+            SOURCE_CODE,
+        );
+        assert_eq!(
+            result,
+            "int main() {\n    foo(3);\n    bar(3);\n    // done\n    return 0;\n\n"
+        );
+    }
+
+    #[test]
+    fn test_keep_bom_and_line_ending() {
+        let source_code = b"\xef\xbb\xbfint main() {\r\n    foo(1);\r\n    return 0;\r\n}\r\n";
+        let execute = |script| execute_bytes(&compile(script), source_code, &Config::default());
+        // No-op script keeps source code as it is
+        assert_eq!(execute("/(comment) @c/d").unwrap().1, source_code);
+        let (_, result) = execute("/(expression_statement) @s/a\\\n\\\n    bar();").unwrap();
+        assert_eq!(
+            result,
+            b"\xef\xbb\xbfint main() {\r\n    foo(1);\r\n    bar();\r\n    return 0;\r\n}\r\n"
+        );
+    }
+
+    #[test]
+    fn test_non_utf8_source_code() {
+        // Latin-1 text in string literal and comment is kept as it is, while the replacement
+        // is UTF-8, `--encoding` decodes source code to avoid mixing them
+        let scripts = compile("s/(identifier) @tbr/\u{e9}/; /(string_literal) @s/c (&)");
+        let source_code = b"char *s = \"caf\xe9\"; // \xe0 la carte\n";
+        let (_, result) = execute_bytes(&scripts, source_code, &Config::default()).unwrap();
+        assert_eq!(
+            result,
+            b"char *\xc3\xa9 = (\"caf\xe9\"); // \xe0 la carte\n"
+        );
+    }
+
+    #[test]
+    fn test_print() {
+        let scripts =
+            compile("/(number_literal) @n/p; s/(number_literal) @tbr/9/g; /(call_expression) @c/p");
+        let (output, source_code) =
+            execute_bytes(&scripts, SOURCE_CODE.as_bytes(), &Config::default()).unwrap();
+        // Printing doesn't change source code
+        assert_eq!(output, b"1\n2\n0\nfoo(9)\nbar(9)\n");
+        assert_eq!(
+            source_code,
+            b"int main() {\n    foo(9);\n    bar(9);\n    return 9;\n}\n"
+        );
+        // Line terminator of a preprocessor directive is printed once
+        let scripts = compile("/(preproc_include) @p/p");
+        for (source_code, expected) in [
+            ("#include <a.h>\nint a;\n", "#include <a.h>\n"),
+            ("#include <a.h>\r\nint a;\r\n", "#include <a.h>\r\n"),
+        ] {
+            let (output, _) =
+                execute_bytes(&scripts, source_code.as_bytes(), &Config::default()).unwrap();
+            assert_eq!(output, expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_compile_queries() {
+        let lang = tree_sitter_c::language();
+        // Queries in blocks are compiled, and the same pattern is compiled once
+        let scripts = parse("/(comment) @c/d; /(call_expression) @c/{ /(comment) @c/p; s/(number_literal) @tbr/0/ }").unwrap();
+        let queries = Queries::new(lang, &scripts).unwrap();
+        assert_eq!(queries.queries.len(), 3);
+        // Errors of queries are reported before executing scripts
+        let mut scripts =
+            CompiledScripts::new(parse("/(comment) @c/{ s/(no_such_node) @tbr/0/ }").unwrap());
+        assert!(scripts.compile(lang).is_err());
+        // Names of captures selected by commands are checked
+        for script in [
+            "/(call_expression) @call/d@nope",
+            "/(comment) @c/{ s@typo/(identifier) @name/x/ }",
+            "s@name=x@typo=y/(identifier) @name//",
+        ] {
+            assert!(Queries::new(lang, &parse(script).unwrap()).is_err());
+        }
+        assert!(Queries::new(lang, &parse("/(call_expression) @call/a@call ;").unwrap()).is_ok());
+        // Captures referred by replacements are checked
+        for script in [
+            "s/(identifier) @tbr/${typo}/",
+            "s@name=${typo}/(identifier) @name//",
+            "/(call_expression) @call/c ${typo}",
+            "/(call_expression) @call/!c ${call}",
+            "1,2c ${call}",
+            "s/(identifier) @tbr/${tbr/",
+        ] {
+            assert!(Queries::new(lang, &parse(script).unwrap()).is_err());
+        }
+        assert!(Queries::new(lang, &parse("/(call_expression) @call/c ${call};").unwrap()).is_ok());
+        assert!(execute_bytes(&scripts, SOURCE_CODE.as_bytes(), &Config::default()).is_err());
+    }
 }
@@ -1,27 +1,52 @@
 use anyhow::{Context, Result};
 
+/// Line number in address, `$` means the last line
+#[derive(Debug, PartialEq)]
+pub enum Line {
+    Number(u32),
+    Last,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Address {
     Pattern(String),
-    Range(u32, u32),
-    Single(u32),
+    /// From the node matched by first pattern to the next node matched by second pattern
+    PatternRange(String, String),
+    Range(Line, Line),
+    Single(Line),
 }
 
 pub enum Options {
     S {
         placeholder: Option<String>,
+        /// Captures replaced by their own content, `@name=content`
+        assignments: Vec<(String, String)>,
         pattern: String,
         replace: String,
+        /// Replace the Nth match, counted from 1
+        occurrence: u32,
+        /// Replace all matches since the Nth one
+        global: bool,
     },
     A {
+        placeholder: Option<String>,
         content: String,
     },
+    /// Options of `d` and `p`
+    D {
+        placeholder: Option<String>,
+    },
+    Block {
+        scripts: Vec<Script>,
+    },
 }
 
 /// Simulate sed's command format
 /// [addr]command[options]
 pub struct Script {
     pub address: Option<Address>,
+    /// `addr!command` applies command to nodes not selected by address
+    pub negated: bool,
     pub command: char,
     pub options: Option<Options>,
 }
@@ -32,7 +57,7 @@ struct Reader {
 }
 
 impl Reader {
-    fn new(text: &String) -> Reader {
+    fn new(text: &str) -> Reader {
         Reader {
             chars: text.chars().collect(),
             pos: 0,
@@ -42,42 +67,38 @@ impl Reader {
     /// Get token from next positon
     fn next(&mut self) -> Option<char> {
         self.pos += 1;
-        if let Some(s) = self.chars.get(self.pos - 1) {
-            Some(*s)
-        } else {
-            None
-        }
+        self.chars.get(self.pos - 1).copied()
     }
 
     /// Peek a token in current position
     fn peek(&self) -> Option<char> {
-        if let Some(s) = self.chars.get(self.pos) {
-            Some(*s)
-        } else {
-            None
-        }
+        self.chars.get(self.pos).copied()
     }
 }
 
 struct Tokenizer {
-    text: String,
     reader: Reader,
 }
 
 #[derive(Debug, PartialEq)]
 enum Token {
     Number(u32),
+    /// Number too large for `u32`
+    Overflow,
     Char(char),
     Symbol(String),
 }
 
-fn parse_number(reader: &mut Reader) -> u32 {
-    let mut num: u32 = 0;
+/// Parse digits as a number, return `None` if it overflows, the digits are consumed anyway
+fn parse_number(reader: &mut Reader) -> Option<u32> {
+    let mut num: Option<u32> = Some(0);
     while let Some(ch) = reader.peek() {
         if !ch.is_ascii_digit() {
             break;
         }
-        num = num * 10 + ch.to_digit(10).unwrap_or(0);
+        num = num
+            .and_then(|num| num.checked_mul(10))
+            .and_then(|num| num.checked_add(ch.to_digit(10).unwrap_or(0)));
         reader.next();
     }
     num
@@ -89,7 +110,7 @@ fn parse_symbol(reader: &mut Reader) -> String {
         if !ch.is_ascii_alphabetic() {
             break;
         }
-        s += &ch.to_string();
+        s.push(ch);
         reader.next();
     }
     s
@@ -98,7 +119,7 @@ fn parse_symbol(reader: &mut Reader) -> String {
 impl Tokenizer {
     fn new(text: String) -> Option<Tokenizer> {
         let reader = Reader::new(&text);
-        Some(Tokenizer { text, reader })
+        Some(Tokenizer { reader })
     }
 
     fn pos(&self) -> usize {
@@ -106,12 +127,9 @@ impl Tokenizer {
     }
 
     fn get_token(&mut self) -> Option<Token> {
-        let last_char = match self.reader.peek() {
-            Some(ch) => ch,
-            None => return None,
-        };
+        let last_char = self.reader.peek()?;
         if last_char.is_ascii_digit() {
-            return Some(Token::Number(parse_number(&mut self.reader)));
+            return Some(parse_number(&mut self.reader).map_or(Token::Overflow, Token::Number));
         }
         if last_char.is_ascii_alphabetic() {
             return Some(Token::Symbol(parse_symbol(&mut self.reader)));
@@ -120,150 +138,338 @@ impl Tokenizer {
         Some(Token::Char(last_char))
     }
 
-    /// Get symbol by spliting with `split`
+    /// Get next token without consuming it
+    fn peek_token(&mut self) -> Option<Token> {
+        let pos = self.pos();
+        let token = self.get_token();
+        self.reader.pos = pos;
+        token
+    }
+
+    /// Get symbol by spliting with `split`, `\` escapes `split` inside the symbol.
+    /// Return `None` if `split` is missing
     fn get_sym(&mut self, split: char) -> Option<Token> {
-        let start_pos = self.pos();
-        while let Some(token) = self.get_token() {
-            match token {
-                Token::Char(c) if c == split => break,
-                _ => (),
+        let mut s = String::new();
+        while let Some(ch) = self.reader.next() {
+            match ch {
+                '\\' if self.reader.peek() == Some(split) => {
+                    s.push(split);
+                    self.reader.next();
+                }
+                c if c == split => return Some(Token::Symbol(s)),
+                c => s.push(c),
             }
         }
-        let mut end_pos = self.pos();
-        // Because of the while loop consume the `split`,
-        // `end_pos` points to the `split`'s position + 1
-        // So, if `end_pos - 1` point to `split`, let end_pos forward 1 position
-        if self.text.chars().nth(end_pos - 1) == Some(split) {
-            end_pos -= 1;
+        None
+    }
+
+    /// Get symbol like `get_sym`, but new lines must be escaped by `\`, like sed's replacement.
+    /// Return `None` if `split` is missing before the end of line
+    fn get_line_sym(&mut self, split: char) -> Option<Token> {
+        let mut s = String::new();
+        while let Some(ch) = self.reader.next() {
+            match ch {
+                '\\' if self.reader.peek() == Some(split) || self.reader.peek() == Some('\n') => {
+                    s.extend(self.reader.next());
+                }
+                '\n' => return None,
+                c if c == split => return Some(Token::Symbol(s)),
+                c => s.push(c),
+            }
         }
-        let selected = match self.text.get(start_pos..end_pos) {
-            Some(s) => s.to_string(),
-            None => return None,
-        };
-        Some(Token::Symbol(selected))
+        None
     }
-}
 
-// Consume white space between address and command
-fn consume_whitespace(token: &mut Option<Token>, tokenizer: &mut Tokenizer) {
-    if let Some(Token::Char(ch)) = token {
-        if *ch == ' ' {
-            while let Some(next) = tokenizer.get_token() {
-                if next != Token::Char(' ') {
-                    *token = Some(next);
-                    break;
+    /// Get capture name after `@`
+    fn get_name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(ch) = self.reader.peek() {
+            if !(ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '.')) {
+                break;
+            }
+            name.push(ch);
+            self.reader.next();
+        }
+        name
+    }
+
+    /// Get text until one of `splits` without consuming it, `\` escapes `splits` inside the text
+    fn get_until(&mut self, splits: &[char]) -> String {
+        let mut s = String::new();
+        while let Some(ch) = self.reader.peek() {
+            if splits.contains(&ch) {
+                break;
+            }
+            self.reader.next();
+            match self.reader.peek() {
+                Some(escaped) if ch == '\\' && splits.contains(&escaped) => {
+                    s.push(escaped);
+                    self.reader.next();
                 }
+                _ => s.push(ch),
+            }
+        }
+        s
+    }
+
+    /// Skip white space between address, command and its arguments
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t') = self.reader.peek() {
+            self.reader.next();
+        }
+    }
+
+    /// Skip white space, new lines, `;` and `#` comments between commands
+    fn skip_separators(&mut self) {
+        while let Some(ch) = self.reader.peek() {
+            match ch {
+                ' ' | '\t' | '\r' | '\n' | ';' => {
+                    self.reader.next();
+                }
+                '#' => {
+                    // Comment lasts until the end of line
+                    while !matches!(self.reader.next(), Some('\n') | None) {}
+                }
+                _ => break,
             }
         }
     }
 }
 
-/// Parse sed script with a hand-written top-down parser
-pub fn parse(script: &str) -> Result<Script> {
-    // TODO parse more sed script
+/// Parse line number or `$` in address
+fn parse_line(token: Option<Token>) -> Result<Line> {
+    match token {
+        Some(Token::Number(0)) => Err(anyhow::format_err!("invalid usage of line address 0")),
+        Some(Token::Number(line)) => Ok(Line::Number(line)),
+        Some(Token::Overflow) => Err(anyhow::format_err!("line address is too large")),
+        Some(Token::Char('$')) => Ok(Line::Last),
+        _ => Err(anyhow::format_err!("address format error")),
+    }
+}
+
+/// Parse optional `@placeholder` after command
+fn parse_placeholder(tokenizer: &mut Tokenizer) -> Result<Option<String>> {
+    if tokenizer.peek_token() != Some(Token::Char('@')) {
+        return Ok(None);
+    }
+    tokenizer.get_token();
+    let name = tokenizer.get_name();
+    if name.is_empty() {
+        return Err(anyhow::format_err!("Missing placeholder"));
+    }
+    Ok(Some(name))
+}
+
+/// Parse `a`/`i` command's text, either `a text` or `a\` followed by text in next line
+fn parse_content(tokenizer: &mut Tokenizer, command: char) -> Result<String> {
+    tokenizer.skip_whitespace();
+    if tokenizer.peek_token() == Some(Token::Char('\\')) {
+        tokenizer.get_token();
+        if tokenizer.get_token() != Some(Token::Char('\n')) {
+            return Err(anyhow::format_err!(
+                "missing content in {} command",
+                command
+            ));
+        }
+    }
+    // Content lasts until the end of line or script
+    let content = tokenizer.get_until(&['\n']);
+    tokenizer.get_token();
+    if content.is_empty() {
+        return Err(anyhow::format_err!(
+            "missing content in {} command",
+            command
+        ));
+    }
+    Ok(content)
+}
+
+/// Parse one command of the script
+fn parse_script(tokenizer: &mut Tokenizer) -> Result<Script> {
     // Script format: [addr]X[options]
-    let mut tokenizer = Tokenizer::new(script.to_string()).context("Fail to tokenizer [SCRIPT]")?;
     let mut token = tokenizer.get_token();
     // Parse address (Optional)
     let address = match token {
-        Some(Token::Number(start)) => match tokenizer.get_token() {
-            Some(Token::Char(',')) => {
-                let end = match tokenizer.get_token() {
-                    Some(Token::Number(end)) => end,
-                    _ => return Err(anyhow::format_err!("Missing end address in [SCRIPT]")),
-                };
-                token = tokenizer.get_token();
-                Some(Address::Range(start, end))
-            }
-            Some(Token::Symbol(s)) => {
-                // When address is single line, next token will be command
-                token = Some(Token::Symbol(s));
-                Some(Address::Single(start))
+        Some(Token::Number(_) | Token::Overflow | Token::Char('$')) => {
+            let start = parse_line(token)?;
+            match tokenizer.get_token() {
+                Some(Token::Char(',')) => {
+                    let end = parse_line(tokenizer.get_token())
+                        .context("Missing end address in [SCRIPT]")?;
+                    token = tokenizer.get_token();
+                    Some(Address::Range(start, end))
+                }
+                Some(next) => {
+                    // When address is single line, next token will be command
+                    token = Some(next);
+                    Some(Address::Single(start))
+                }
+                None => return Err(anyhow::format_err!("address format error")),
             }
-            _ => return Err(anyhow::format_err!("address format error")),
-        },
-        Some(Token::Char(ch)) if ch == '/' => {
+        }
+        Some(Token::Char('/')) => {
             let pattern = tokenizer.get_sym('/');
-            match pattern {
-                Some(Token::Symbol(s)) => {
-                    token = tokenizer.get_token();
-                    Some(Address::Pattern(s))
+            let pattern = match pattern {
+                Some(Token::Symbol(s)) => s,
+                _ => return Err(anyhow::format_err!("unterminated address pattern")),
+            };
+            token = tokenizer.get_token();
+            if token == Some(Token::Char(',')) {
+                if tokenizer.get_token() != Some(Token::Char('/')) {
+                    return Err(anyhow::format_err!("Missing end pattern in [SCRIPT]"));
                 }
-                _ => return Err(anyhow::format_err!("address format error")),
+                let end = match tokenizer.get_sym('/') {
+                    Some(Token::Symbol(s)) => s,
+                    _ => return Err(anyhow::format_err!("unterminated address pattern")),
+                };
+                token = tokenizer.get_token();
+                Some(Address::PatternRange(pattern, end))
+            } else {
+                Some(Address::Pattern(pattern))
             }
         }
         _ => None,
     };
+    if token == Some(Token::Char(' ')) || token == Some(Token::Char('\t')) {
+        tokenizer.skip_whitespace();
+        token = tokenizer.get_token();
+    }
+    // Parse negation (Optional)
+    let negated = token == Some(Token::Char('!'));
+    if negated {
+        if address.is_none() {
+            return Err(anyhow::format_err!("missing address before '!'"));
+        }
+        tokenizer.skip_whitespace();
+        token = tokenizer.get_token();
+    }
     // Parse command
-    consume_whitespace(&mut token, &mut tokenizer);
     let command = match token {
-        Some(Token::Symbol(s)) => {
-            let next_ch = s.chars().next().context("missing command")?;
-            token = tokenizer.get_token();
-            next_ch
-        }
+        Some(Token::Symbol(s)) if s.len() == 1 => s.chars().next().context("missing command")?,
+        Some(Token::Symbol(s)) => return Err(anyhow::format_err!("unknown command: `{}`", s)),
+        Some(Token::Char('{')) => '{',
         _ => return Err(anyhow::format_err!("missing command")),
     };
     // Parse options
     let options = match command {
         's' => {
-            // Parse placeholder (Extend)
-            let placeholder = match token {
-                Some(Token::Char(ch)) if ch == '@' => match tokenizer.get_token() {
-                    Some(Token::Symbol(s)) => {
-                        token = tokenizer.get_token();
-                        Some(s)
-                    }
-                    _ => return Err(anyhow::format_err!("Missing placeholder")),
-                },
-                _ => None,
-            };
-            if let Some(Token::Char(ch)) = token {
-                if ch != '/' {
-                    return Err(anyhow::format_err!("Missing '/' in argument"));
+            // Parse placeholder and assignments (Extend): [@name][@name=content]...
+            let mut placeholder = None;
+            let mut assignments = vec![];
+            let mut token = tokenizer.get_token();
+            while token == Some(Token::Char('@')) {
+                let name = tokenizer.get_name();
+                if name.is_empty() {
+                    return Err(anyhow::format_err!("Missing placeholder"));
                 }
+                if tokenizer.peek_token() == Some(Token::Char('=')) {
+                    tokenizer.get_token();
+                    assignments.push((name, tokenizer.get_until(&['@', '/'])));
+                } else if placeholder.is_none() {
+                    placeholder = Some(name);
+                } else {
+                    return Err(anyhow::format_err!("Multiple placeholders without content"));
+                }
+                token = tokenizer.get_token();
+            }
+            if token != Some(Token::Char('/')) {
+                return Err(anyhow::format_err!("Missing '/' in argument"));
             }
             let pattern = match tokenizer.get_sym('/') {
                 Some(Token::Symbol(patten)) => patten,
-                _ => return Err(anyhow::format_err!("missing pattern")),
+                _ => return Err(anyhow::format_err!("unterminated `s` command")),
             };
-            let replace = match tokenizer.get_sym('/') {
+            let replace = match tokenizer.get_line_sym('/') {
                 Some(Token::Symbol(replace)) => replace,
-                _ => return Err(anyhow::format_err!("missing pattern")),
+                _ => return Err(anyhow::format_err!("unterminated `s` command")),
             };
+            // Parse flags: [N][g]
+            let occurrence = match tokenizer.peek_token() {
+                Some(Token::Number(0)) => {
+                    return Err(anyhow::format_err!(
+                        "number option to `s` command may not be zero"
+                    ))
+                }
+                Some(Token::Number(n)) => {
+                    tokenizer.get_token();
+                    n
+                }
+                Some(Token::Overflow) => {
+                    return Err(anyhow::format_err!(
+                        "number option to `s` command is too large"
+                    ))
+                }
+                _ => 1,
+            };
+            let global = match tokenizer.peek_token() {
+                Some(Token::Symbol(flag)) if flag == "g" => {
+                    tokenizer.get_token();
+                    true
+                }
+                Some(Token::Symbol(flag)) => {
+                    return Err(anyhow::format_err!("unknown option to `s`: {}", flag))
+                }
+                _ => false,
+            };
+            if placeholder.is_none() && !assignments.is_empty() && !replace.is_empty() {
+                return Err(anyhow::format_err!("Missing placeholder of replacement"));
+            }
             Some(Options::S {
                 placeholder,
+                assignments,
                 pattern,
                 replace,
+                occurrence,
+                global,
             })
         }
-        'a' | 'i' => {
-            consume_whitespace(&mut token, &mut tokenizer);
-            let content = match token {
-                Some(Token::Char('\\')) => {
-                    let next_line = tokenizer.get_token();
-                    if next_line != Some(Token::Char('\n')) {
-                        return Err(anyhow::format_err!("missing content in a command"));
-                    }
-                    match tokenizer.get_sym('\n') {
-                        Some(Token::Symbol(s)) => s,
-                        _ => return Err(anyhow::format_err!("missing content in a command")),
-                    }
-                }
-                Some(Token::Symbol(s)) => s,
-                _ => return Err(anyhow::format_err!("missing content in a command")),
-            };
-            Some(Options::A { content })
-        }
-        _ => None,
+        'a' | 'i' | 'c' => Some(Options::A {
+            placeholder: parse_placeholder(tokenizer)?,
+            content: parse_content(tokenizer, command)?,
+        }),
+        '{' => Some(Options::Block {
+            scripts: parse_commands(tokenizer, true)?,
+        }),
+        'd' | 'p' => Some(Options::D {
+            placeholder: parse_placeholder(tokenizer)?,
+        }),
+        other => return Err(anyhow::format_err!("unknown command: `{}`", other)),
     };
     Ok(Script {
         address,
+        negated,
         command,
         options,
     })
 }
 
+/// Parse commands separated by `;` or new lines, until the end of script or the `}` of a block
+fn parse_commands(tokenizer: &mut Tokenizer, in_block: bool) -> Result<Vec<Script>> {
+    let mut scripts = vec![];
+    loop {
+        tokenizer.skip_separators();
+        match tokenizer.peek_token() {
+            None if in_block => return Err(anyhow::format_err!("missing '}}' in [SCRIPT]")),
+            None => return Ok(scripts),
+            Some(Token::Char('}')) if in_block => {
+                tokenizer.get_token();
+                return Ok(scripts);
+            }
+            Some(Token::Char('}')) => {
+                return Err(anyhow::format_err!("unexpected '}}' in [SCRIPT]"))
+            }
+            _ => scripts.push(parse_script(tokenizer)?),
+        }
+    }
+}
+
+/// Parse sed script with a hand-written top-down parser
+pub fn parse(script: &str) -> Result<Vec<Script>> {
+    // TODO parse more sed script
+    let mut tokenizer = Tokenizer::new(script.to_string()).context("Fail to tokenizer [SCRIPT]")?;
+    parse_commands(&mut tokenizer, false)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -286,7 +492,7 @@ mod test {
 
     #[test]
     fn test_basic_parse() {
-        let result = parse("s/aaa/bbb/").unwrap();
+        let result = parse("s/aaa/bbb/").unwrap().remove(0);
         match result.options {
             Some(Options::S {
                 pattern, replace, ..
@@ -300,8 +506,11 @@ mod test {
 
     #[test]
     fn test_address_parse() {
-        let result = parse("1,2s/aaa/bbb/").unwrap();
-        assert_eq!(result.address, Some(Address::Range(1, 2)));
+        let result = parse("1,2s/aaa/bbb/").unwrap().remove(0);
+        assert_eq!(
+            result.address,
+            Some(Address::Range(Line::Number(1), Line::Number(2)))
+        );
         assert_eq!(result.command, 's');
         match result.options {
             Some(Options::S {
@@ -312,20 +521,33 @@ mod test {
             }
             _ => panic!("parse fail"),
         }
-        let result = parse("100s/aaa/bbb/").unwrap();
-        assert_eq!(result.address, Some(Address::Single(100)))
+        let result = parse("100s/aaa/bbb/").unwrap().remove(0);
+        assert_eq!(result.address, Some(Address::Single(Line::Number(100))));
+        let result = parse("10,$d").unwrap().remove(0);
+        assert_eq!(
+            result.address,
+            Some(Address::Range(Line::Number(10), Line::Last))
+        );
+        let result = parse("$ p").unwrap().remove(0);
+        assert_eq!(result.address, Some(Address::Single(Line::Last)));
+        assert_eq!(result.command, 'p');
+        assert!(parse("0d").is_err());
     }
 
     #[test]
     fn test_extend_parse() {
-        let result = parse("1,2s@placeholder/aaa/bbb/").unwrap();
-        assert_eq!(result.address, Some(Address::Range(1, 2)));
+        let result = parse("1,2s@placeholder/aaa/bbb/").unwrap().remove(0);
+        assert_eq!(
+            result.address,
+            Some(Address::Range(Line::Number(1), Line::Number(2)))
+        );
         assert_eq!(result.command, 's');
         match result.options {
             Some(Options::S {
                 placeholder,
                 pattern,
                 replace,
+                ..
             }) => {
                 assert_eq!(placeholder, Some(String::from("placeholder")));
                 assert_eq!(pattern, String::from("aaa"));
@@ -338,7 +560,7 @@ mod test {
     #[test]
     fn test_tree_sitter_query() {
         let query = r#"s/(argument_list (_) @tbr)/"Just Monika"/"#;
-        let result = parse(query).unwrap();
+        let result = parse(query).unwrap().remove(0);
         match result.options {
             Some(Options::S {
                 pattern, replace, ..
@@ -353,7 +575,7 @@ mod test {
     #[test]
     fn test_pattern_address() {
         let query = "/(call_expression function: (identifier @func) (#eq? @func \"puts\"))/ d";
-        let result = parse(query).unwrap();
+        let result = parse(query).unwrap().remove(0);
         assert_eq!(result.command, 'd');
         assert_eq!(
             result.address,
@@ -366,10 +588,10 @@ mod test {
     #[test]
     fn test_parse_append() {
         let script = r#"/(call_expression)/ a text"#;
-        let result = parse(script).unwrap();
+        let result = parse(script).unwrap().remove(0);
         assert_eq!(result.command, 'a');
         match result.options {
-            Some(Options::A { content }) => {
+            Some(Options::A { content, .. }) => {
                 assert_eq!(content, String::from("text"))
             }
             _ => panic!(""),
@@ -377,13 +599,244 @@ mod test {
         // Second format
         let script = r#"/(call_expression)/ a\
 a long long text"#;
-        let result = parse(script).unwrap();
+        let result = parse(script).unwrap().remove(0);
         assert_eq!(result.command, 'a');
         match result.options {
-            Some(Options::A { content }) => {
+            Some(Options::A { content, .. }) => {
                 assert_eq!(content, String::from("a long long text"))
             }
             _ => panic!(""),
         }
     }
+
+    #[test]
+    fn test_parse_program() {
+        let script = "/(comment) @c/d; s/(identifier) @tbr/x/\n/(call_expression) @call/p";
+        let result = parse(script).unwrap();
+        let commands: Vec<char> = result.iter().map(|s| s.command).collect();
+        assert_eq!(commands, vec!['d', 's', 'p']);
+        assert_eq!(
+            result[2].address,
+            Some(Address::Pattern(String::from("(call_expression) @call")))
+        );
+    }
+
+    #[test]
+    fn test_parse_block() {
+        let script = "/(function_definition) @f/{ p; /(comment) @c/d }; 1d";
+        let result = parse(script).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].command, '{');
+        match &result[0].options {
+            Some(Options::Block { scripts }) => {
+                assert_eq!(scripts.len(), 2);
+                assert_eq!(scripts[0].command, 'p');
+                assert_eq!(scripts[0].address, None);
+                assert_eq!(scripts[1].command, 'd');
+            }
+            _ => panic!("parse fail"),
+        }
+        assert_eq!(result[1].address, Some(Address::Single(Line::Number(1))));
+        assert!(parse("/(comment) @c/{ d").is_err());
+        assert!(parse("d }").is_err());
+    }
+
+    #[test]
+    fn test_parse_comment() {
+        let script = r#"# Remove debug output
+/(call_expression
+  function: (identifier) @func
+  (#eq? @func "debug")) @call/d # trailing comment
+
+# Rename
+s/(identifier) @tbr (#eq? @tbr "foo")/bar/
+"#;
+        let result = parse(script).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].command, 'd');
+        assert_eq!(
+            result[0].address,
+            Some(Address::Pattern(String::from(
+                "(call_expression\n  function: (identifier) @func\n  (#eq? @func \"debug\")) @call"
+            )))
+        );
+        assert_eq!(result[1].command, 's');
+    }
+
+    #[test]
+    fn test_pattern_range_address() {
+        let script = r#"/(comment) @begin (#eq? @begin "\/\/ BEGIN")/,/(comment) @end/ s/(number_literal) @tbr/0/"#;
+        let result = parse(script).unwrap().remove(0);
+        assert_eq!(
+            result.address,
+            Some(Address::PatternRange(
+                String::from(r#"(comment) @begin (#eq? @begin "// BEGIN")"#),
+                String::from("(comment) @end")
+            ))
+        );
+        assert_eq!(result.command, 's');
+        assert!(parse("/(comment) @c/,d").is_err());
+    }
+
+    #[test]
+    fn test_negated_address() {
+        let result = parse("/(comment) @c/!d").unwrap().remove(0);
+        assert!(result.negated);
+        assert_eq!(result.command, 'd');
+        let result = parse("1,3 ! s/(identifier) @tbr/x/").unwrap().remove(0);
+        assert!(result.negated);
+        assert_eq!(
+            result.address,
+            Some(Address::Range(Line::Number(1), Line::Number(3)))
+        );
+        assert!(!parse("/(comment) @c/d").unwrap().remove(0).negated);
+        assert!(parse("!d").is_err());
+    }
+
+    #[test]
+    fn test_unterminated_delimiter() {
+        let error = |script: &str| parse(script).err().map(|e| e.to_string());
+        assert_eq!(
+            error("s/(x) @tbr/Q").as_deref(),
+            Some("unterminated `s` command")
+        );
+        // Next line isn't swallowed into the replacement
+        assert_eq!(
+            error("s/(x) @tbr/Q\n/(comment) @c/d").as_deref(),
+            Some("unterminated `s` command")
+        );
+        match parse("s/(x) @tbr/a\\\nb/").unwrap().remove(0).options {
+            Some(Options::S { replace, .. }) => assert_eq!(replace, "a\nb"),
+            _ => panic!("parse fail"),
+        }
+        assert_eq!(
+            error("s/(x) @tbr").as_deref(),
+            Some("unterminated `s` command")
+        );
+        assert_eq!(
+            error("/(comment) @c d").as_deref(),
+            Some("unterminated address pattern")
+        );
+        assert_eq!(
+            error("/(comment) @c/,/(comment) @d p").as_deref(),
+            Some("unterminated address pattern")
+        );
+        // Content of `a` may end with the script
+        assert!(parse("/(comment) @c/a text").is_ok());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let flags = |script: &str| match parse(script).unwrap().remove(0).options {
+            Some(Options::S {
+                occurrence, global, ..
+            }) => (occurrence, global),
+            _ => panic!("parse fail"),
+        };
+        assert_eq!(flags("s/aaa/bbb/"), (1, false));
+        assert_eq!(flags("s/aaa/bbb/g"), (1, true));
+        assert_eq!(flags("s/aaa/bbb/3"), (3, false));
+        assert_eq!(flags("s/aaa/bbb/3g; p"), (3, true));
+        assert!(parse("s/aaa/bbb/0").is_err());
+        assert!(parse("s/aaa/bbb/x").is_err());
+        // Numbers out of range are errors instead of overflowing
+        assert_eq!(flags("s/aaa/bbb/4294967295"), (u32::MAX, false));
+        assert!(parse("s/aaa/bbb/99999999999").is_err());
+        assert!(parse("99999999999d").is_err());
+        assert!(parse("1,4294967296d").is_err());
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        let script = "s@name=new_name@args=(\\@x)/(call_expression function: (_) @name arguments: (_) @args)//";
+        let result = parse(script).unwrap().remove(0);
+        match result.options {
+            Some(Options::S {
+                placeholder,
+                assignments,
+                replace,
+                ..
+            }) => {
+                assert_eq!(placeholder, None);
+                assert_eq!(
+                    assignments,
+                    vec![
+                        (String::from("name"), String::from("new_name")),
+                        (String::from("args"), String::from("(@x)"))
+                    ]
+                );
+                assert_eq!(replace, String::from(""));
+            }
+            _ => panic!("parse fail"),
+        }
+        let result = parse("s@the-function@args=()/(_)/foo/").unwrap().remove(0);
+        match result.options {
+            Some(Options::S {
+                placeholder,
+                assignments,
+                ..
+            }) => {
+                assert_eq!(placeholder, Some(String::from("the-function")));
+                assert_eq!(assignments.len(), 1);
+            }
+            _ => panic!("parse fail"),
+        }
+        assert!(parse("s@a=x/(_)/y/").is_err());
+    }
+
+    #[test]
+    fn test_parse_command_placeholder() {
+        let result = parse("/(call_expression function: (_) @func) @call/d@call")
+            .unwrap()
+            .remove(0);
+        match result.options {
+            Some(Options::D { placeholder }) => {
+                assert_eq!(placeholder, Some(String::from("call")))
+            }
+            _ => panic!("parse fail"),
+        }
+        let result = parse("/(call_expression) @call/p").unwrap().remove(0);
+        match result.options {
+            Some(Options::D { placeholder }) => assert_eq!(placeholder, None),
+            _ => panic!("parse fail"),
+        }
+        let result = parse("/(call_expression) @call/i@call /* call */")
+            .unwrap()
+            .remove(0);
+        match result.options {
+            Some(Options::A {
+                placeholder,
+                content,
+            }) => {
+                assert_eq!(placeholder, Some(String::from("call")));
+                assert_eq!(content, String::from("/* call */"));
+            }
+            _ => panic!("parse fail"),
+        }
+    }
+
+    #[test]
+    fn test_parse_change() {
+        let result = parse("/(identifier) @id/c@id ${id}_new").unwrap().remove(0);
+        assert_eq!(result.command, 'c');
+        match result.options {
+            Some(Options::A {
+                placeholder,
+                content,
+            }) => {
+                assert_eq!(placeholder, Some(String::from("id")));
+                assert_eq!(content, String::from("${id}_new"));
+            }
+            _ => panic!("parse fail"),
+        }
+        let result = parse("2c\\\nint a;\\\nint b;\nd").unwrap();
+        assert_eq!(result.len(), 2);
+        match &result[0].options {
+            Some(Options::A { content, .. }) => {
+                assert_eq!(content, &String::from("int a;\nint b;"))
+            }
+            _ => panic!("parse fail"),
+        }
+        assert!(parse("1c").is_err());
+    }
 }
@@ -0,0 +1,32 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Directory in the system's temporary directory for a test, removed when it's dropped,
+/// so it's cleaned up even if the test fails
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Create an empty directory named by `name` and the process id
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("tree-sed-{}-{}", name, process::id()));
+        // Remove the directory left by an aborted run
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}